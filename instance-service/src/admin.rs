@@ -0,0 +1,108 @@
+//! Access control for the `/admin` routes. They share the public listener with the sieve API, so
+//! every admin handler takes an [`Admin`] extractor that checks for `Authorization: Bearer
+//! <ADMIN_TOKEN>`. Without `ADMIN_TOKEN` the admin routes are switched off entirely.
+
+use std::future::{Ready, ready};
+
+use actix_web::{FromRequest, HttpRequest, dev::Payload, http::header, web};
+
+use crate::error::ApiError;
+
+/// The token admin requests have to present, loaded once at startup.
+pub struct AdminToken(Option<String>);
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> AdminToken {
+        AdminToken(token)
+    }
+
+    pub fn from_env() -> anyhow::Result<AdminToken> {
+        match std::env::var("ADMIN_TOKEN") {
+            Ok(val) if val.trim().is_empty() => Err(anyhow::anyhow!("Invalid value '' for ADMIN_TOKEN: must not be empty")),
+            Ok(val) => Ok(AdminToken::new(Some(val))),
+            Err(_) => Ok(AdminToken::new(None)),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    fn check(&self, req: &HttpRequest) -> Result<(), ApiError> {
+        let expected = self.0.as_deref()
+            .ok_or_else(|| ApiError::Forbidden(String::from("admin routes are disabled - set ADMIN_TOKEN to enable them")))?;
+        let presented = req.headers().get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| ApiError::Unauthorized(String::from("admin routes need an 'Authorization: Bearer <token>' header")))?;
+        if !constant_time_eq(presented.as_bytes(), expected.as_bytes()) {
+            return Err(ApiError::Unauthorized(String::from("admin token doesn't match")));
+        }
+        Ok(())
+    }
+}
+
+/// Proof that the request carried the admin token. Taking it as a handler argument is what makes
+/// a route admin-only.
+#[derive(Debug)]
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = ApiError;
+    type Future = Ready<Result<Admin, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let res = match req.app_data::<web::Data<AdminToken>>() {
+            Some(token) => token.check(req),
+            None => AdminToken(None).check(req),
+        };
+        if let Err(e) = &res {
+            tracing::warn!("Refusing {} {}: {}", req.method(), req.path(), e);
+        }
+        ready(res.map(|_| Admin))
+    }
+}
+
+// so a wrong guess takes as long as a nearly right one
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpResponse, http::StatusCode, test};
+
+    #[actix_web::test]
+    async fn admin_routes_need_the_token() {
+        let guarded = |_: Admin| async { HttpResponse::NoContent().finish() };
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(AdminToken::new(Some(String::from("s3cret")))))
+            .route("/admin/thing", web::delete().to(guarded))).await;
+
+        for (auth, status) in [
+            (None, StatusCode::UNAUTHORIZED),
+            (Some("Bearer nope"), StatusCode::UNAUTHORIZED),
+            (Some("s3cret"), StatusCode::UNAUTHORIZED),
+            (Some("Bearer s3cret"), StatusCode::NO_CONTENT),
+        ] {
+            let mut req = test::TestRequest::delete().uri("/admin/thing");
+            if let Some(auth) = auth {
+                req = req.insert_header((header::AUTHORIZATION, auth));
+            }
+            let res = test::call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), status, "{:?}", auth);
+        }
+    }
+
+    #[actix_web::test]
+    async fn admin_routes_are_off_without_a_token() {
+        let guarded = |_: Admin| async { HttpResponse::NoContent().finish() };
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(AdminToken::new(None)))
+            .route("/admin/thing", web::delete().to(guarded))).await;
+
+        let req = test::TestRequest::delete().uri("/admin/thing").insert_header((header::AUTHORIZATION, "Bearer ")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    }
+}
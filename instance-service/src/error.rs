@@ -0,0 +1,214 @@
+//! Error model for the API. Every failure is answered with a JSON problem body
+//! (`{"type", "title", "status", "detail"}`, after RFC 7807) so callers never have to parse text.
+
+use std::fmt;
+
+use actix_web::{HttpResponse, ResponseError, error::{JsonPayloadError, PathError, QueryPayloadError}, http::StatusCode};
+use serde_json::json;
+use sieve_protocol::{VersionError, integrity::IntegrityError};
+
+use crate::lifecycle::TransitionError;
+
+const MAX_ID_LEN: usize = 128;
+pub const MAX_REASON_LEN: usize = 4096;
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request parsed but its content isn't acceptable.
+    Validation(String),
+    /// The body couldn't be read or parsed at all.
+    Payload(JsonPayloadError),
+    Query(String),
+    Version(VersionError),
+    Integrity(IntegrityError),
+    NotFound(String),
+    /// An admin request without a valid admin token.
+    Unauthorized(String),
+    /// An admin request while the admin routes are switched off.
+    Forbidden(String),
+    Conflict(TransitionError),
+    /// A worker resubmitted a result that doesn't match the one already accepted.
+    ResultConflict(String),
+    Storage(anyhow::Error),
+    /// Answer chosen by a fault injection rule rather than the handler.
+    Injected(StatusCode),
+}
+
+impl ApiError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::Validation(_) => "validation",
+            ApiError::Payload(_) => "payload",
+            ApiError::Query(_) => "query",
+            ApiError::Version(_) => "version",
+            ApiError::Integrity(_) => "integrity",
+            ApiError::NotFound(_) => "not-found",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::ResultConflict(_) => "result-conflict",
+            ApiError::Storage(_) => "storage",
+            ApiError::Injected(_) => "injected-fault",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Validation(msg) | ApiError::Query(msg) | ApiError::NotFound(msg) | ApiError::ResultConflict(msg)
+            | ApiError::Unauthorized(msg) | ApiError::Forbidden(msg) => write!(f, "{}", msg),
+            ApiError::Payload(e) => write!(f, "{}", e),
+            ApiError::Version(e) => write!(f, "{}", e),
+            ApiError::Integrity(e) => write!(f, "{}", e),
+            ApiError::Conflict(e) => write!(f, "{}", e),
+            ApiError::Storage(e) => write!(f, "unable to persist worker: {}", e),
+            ApiError::Injected(status) => write!(f, "{} injected by a fault rule", status.as_u16()),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Validation(_) | ApiError::Query(_) | ApiError::Version(_) => StatusCode::BAD_REQUEST,
+            ApiError::Payload(e) => match e {
+                JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                JsonPayloadError::ContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                _ => StatusCode::BAD_REQUEST,
+            },
+            // corrupted payloads are a bad request, anything wrong with the signature means the
+            // sender couldn't prove it holds the key
+            ApiError::Integrity(IntegrityError::DigestMismatch { .. }) => StatusCode::BAD_REQUEST,
+            ApiError::Integrity(_) => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Conflict(_) | ApiError::ResultConflict(_) => StatusCode::CONFLICT,
+            ApiError::Storage(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Injected(status) => *status,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let mut body = json!({
+            "type": self.kind(),
+            "title": status.canonical_reason().unwrap_or("Error"),
+            "status": status.as_u16(),
+            "detail": self.to_string(),
+        });
+        match self {
+            ApiError::Version(e) => {
+                body["oldest_supported"] = json!(e.oldest_supported);
+                body["newest_supported"] = json!(e.newest_supported);
+            },
+            ApiError::Conflict(e) => body["worker_status"] = json!(e.from),
+            _ => {},
+        }
+        HttpResponse::build(status)
+            .content_type("application/problem+json")
+            .body(body.to_string())
+    }
+}
+
+impl From<VersionError> for ApiError {
+    fn from(e: VersionError) -> Self {
+        ApiError::Version(e)
+    }
+}
+
+impl From<IntegrityError> for ApiError {
+    fn from(e: IntegrityError) -> Self {
+        ApiError::Integrity(e)
+    }
+}
+
+impl From<TransitionError> for ApiError {
+    fn from(e: TransitionError) -> Self {
+        ApiError::Conflict(e)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError::Storage(e)
+    }
+}
+
+// extractor error handlers, so malformed input gets the same body as everything else
+pub fn json_error(err: JsonPayloadError, _req: &actix_web::HttpRequest) -> actix_web::Error {
+    ApiError::Payload(err).into()
+}
+
+pub fn query_error(err: QueryPayloadError, _req: &actix_web::HttpRequest) -> actix_web::Error {
+    ApiError::Query(err.to_string()).into()
+}
+
+pub fn path_error(err: PathError, _req: &actix_web::HttpRequest) -> actix_web::Error {
+    ApiError::Query(err.to_string()).into()
+}
+
+/// Worker IDs end up in log lines, Redis keys and metrics, so only allow what a UUID or pod name
+/// could contain.
+pub fn validate_id(id: &str) -> Result<(), ApiError> {
+    if id.is_empty() {
+        return Err(ApiError::Validation(String::from("id must not be empty")));
+    }
+    if id.len() > MAX_ID_LEN {
+        return Err(ApiError::Validation(format!("id is {} bytes, the limit is {}", id.len(), MAX_ID_LEN)));
+    }
+    if let Some(c) = id.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))) {
+        return Err(ApiError::Validation(format!("id contains '{}' - only letters, digits, '-', '_' and '.' are allowed", c.escape_default())));
+    }
+    Ok(())
+}
+
+/// Sieves send primes in strictly ascending order. Anything else is a broken or hand-crafted
+/// payload, and would make the recorded max prime wrong.
+pub fn validate_primes(primes: &[u64]) -> Result<(), ApiError> {
+    if primes.is_empty() {
+        return Err(ApiError::Validation(String::from("primes must not be empty")));
+    }
+    if primes[0] < 2 {
+        return Err(ApiError::Validation(format!("{} is not a prime", primes[0])));
+    }
+    if let Some(i) = primes.windows(2).position(|w| w[0] >= w[1]) {
+        return Err(ApiError::Validation(format!("primes must be strictly ascending - {} is followed by {}", primes[i], primes[i + 1])));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_restricted() {
+        assert!(validate_id("5d0a6a5e-0d6f-4b8e-9d5c-1c0f6f0c2a11").is_ok());
+        assert!(validate_id("prime-sieve-instance-3.run_1").is_ok());
+        assert!(validate_id("").is_err());
+        assert!(validate_id("a b").is_err());
+        assert!(validate_id("prime-gen:x:worker").is_err());
+        assert!(validate_id(&"a".repeat(MAX_ID_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn primes_must_ascend() {
+        assert!(validate_primes(&[2, 3, 5]).is_ok());
+        assert!(validate_primes(&[]).is_err());
+        assert!(validate_primes(&[1, 2]).is_err());
+        assert!(validate_primes(&[2, 5, 3]).is_err());
+        assert!(validate_primes(&[2, 3, 3]).is_err());
+    }
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        let mismatch = ApiError::Integrity(IntegrityError::DigestMismatch { expected: String::from("aa"), received: String::from("bb") });
+        assert_eq!(mismatch.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(ApiError::Integrity(IntegrityError::MissingSignature).status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(ApiError::Integrity(IntegrityError::BadSignature).status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(ApiError::Payload(JsonPayloadError::Overflow { limit: 1 }).status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(ApiError::Storage(anyhow::anyhow!("down")).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
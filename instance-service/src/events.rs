@@ -0,0 +1,247 @@
+//! Live feed of worker activity for watching a run as it happens.
+//!
+//! Every change is appended to a bounded in-memory log with an increasing offset and broadcast to
+//! subscribers. `GET /events` serves the feed as Server-Sent Events, or as a WebSocket when the
+//! request asks for an upgrade. `?from=<offset>` (or SSE's `Last-Event-ID`) replays whatever is
+//! still retained from that offset before switching to live events.
+
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{AppData, PrimeResult, Worker, WorkerStatus};
+
+pub const DEFAULT_CAPACITY: usize = 10000;
+// comment lines sent on idle SSE streams so proxies don't close them
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Register,
+    Heartbeat,
+    Result,
+    // a retried result that matched the one already held
+    Duplicate,
+    Failure,
+    Timeout,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Event {
+    pub offset: u64,
+    pub at: DateTime<Utc>,
+    pub kind: EventKind,
+    pub id: String,
+    pub status: WorkerStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub results: Option<PrimeResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+}
+
+struct Retained {
+    events: VecDeque<Event>,
+    next_offset: u64,
+}
+
+pub struct EventLog {
+    retained: Mutex<Retained>,
+    capacity: usize,
+    tx: broadcast::Sender<Event>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> EventLog {
+        let capacity = capacity.max(1);
+        let (tx, _) = broadcast::channel(capacity);
+        EventLog {
+            retained: Mutex::new(Retained { events: VecDeque::with_capacity(capacity), next_offset: 0 }),
+            capacity,
+            tx,
+        }
+    }
+
+    /// Appends an event for the worker's current state and returns its offset.
+    pub fn publish(&self, kind: EventKind, worker: &Worker) -> u64 {
+        let mut retained = self.retained.lock().unwrap();
+        let offset = retained.next_offset;
+        let event = Event {
+            offset,
+            at: Utc::now(),
+            kind,
+            id: worker.id.clone(),
+            status: worker.status,
+            results: worker.results.clone(),
+            failure: worker.failure.clone(),
+        };
+        retained.next_offset += 1;
+        if retained.events.len() == self.capacity {
+            retained.events.pop_front();
+        }
+        retained.events.push_back(event.clone());
+        // sent under the lock so a subscriber never sees an event both in its replay and live.
+        // no receivers just means nobody is watching
+        let _ = self.tx.send(event);
+        offset
+    }
+
+    /// Retained events from `from` onwards plus a receiver for everything after them.
+    pub fn subscribe(&self, from: Option<u64>) -> (Vec<Event>, broadcast::Receiver<Event>) {
+        let retained = self.retained.lock().unwrap();
+        let replay = match from {
+            Some(from) => retained.events.iter().filter(|e| e.offset >= from).cloned().collect(),
+            None => Vec::new(),
+        };
+        (replay, self.tx.subscribe())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventParams {
+    from: Option<u64>,
+}
+
+#[tracing::instrument(skip(store, req, body))]
+pub async fn stream_events(store: web::Data<AppData>, params: web::Query<EventParams>, req: HttpRequest, body: web::Payload) -> actix_web::Result<HttpResponse> {
+    // reconnecting EventSource clients send the last offset they saw
+    let last_seen = req.headers().get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|v| v + 1);
+    let from = params.from.or(last_seen);
+    let (replay, rx) = store.events.subscribe(from);
+
+    let wants_websocket = req.headers().get("upgrade")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    if wants_websocket {
+        let (resp, session, messages) = actix_ws::handle(&req, body)?;
+        actix_web::rt::spawn(websocket_feed(session, messages, replay, rx));
+        return Ok(resp);
+    }
+
+    tracing::info!("New SSE subscriber replaying {} events", replay.len());
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("cache-control", "no-cache"))
+        .streaming(sse_stream(replay, rx)))
+}
+
+fn sse_frame(event: &Event) -> web::Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    web::Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", event.offset, kind_name(event.kind), data))
+}
+
+fn kind_name(kind: EventKind) -> &'static str {
+    match kind {
+        EventKind::Register => "register",
+        EventKind::Heartbeat => "heartbeat",
+        EventKind::Result => "result",
+        EventKind::Duplicate => "duplicate",
+        EventKind::Failure => "failure",
+        EventKind::Timeout => "timeout",
+    }
+}
+
+fn sse_stream(replay: Vec<Event>, rx: broadcast::Receiver<Event>) -> impl futures::Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    let replayed = futures::stream::iter(replay.into_iter().map(|e| Ok(sse_frame(&e))));
+    let live = futures::stream::unfold(Some(rx), |rx| async move {
+        let mut rx = rx?;
+        let frame = tokio::select! {
+            received = rx.recv() => match received {
+                Ok(event) => sse_frame(&event),
+                // the subscriber fell behind the buffer - close so the client reconnects with
+                // Last-Event-ID and catches up from the retained log
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    return Some((Ok(web::Bytes::from(format!(": lagged by {} events\n\n", n))), None));
+                },
+                Err(broadcast::error::RecvError::Closed) => return None,
+            },
+            _ = actix_web::rt::time::sleep(KEEP_ALIVE) => web::Bytes::from_static(b": keep-alive\n\n"),
+        };
+        Some((Ok(frame), Some(rx)))
+    });
+    replayed.chain(live)
+}
+
+async fn websocket_feed(mut session: actix_ws::Session, mut messages: actix_ws::MessageStream, replay: Vec<Event>, mut rx: broadcast::Receiver<Event>) {
+    for event in replay {
+        if session.text(serde_json::to_string(&event).unwrap_or_default()).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            received = rx.recv() => match received {
+                Ok(event) => {
+                    if session.text(serde_json::to_string(&event).unwrap_or_default()).await.is_err() {
+                        return;
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    let reason = format!("lagged by {} events - reconnect with ?from=<last offset + 1>", n);
+                    let _ = session.close(Some(actix_ws::CloseReason { code: actix_ws::CloseCode::Again, description: Some(reason) })).await;
+                    return;
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            msg = messages.next() => match msg {
+                Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                },
+                Some(Ok(actix_ws::Message::Close(_))) | None | Some(Err(_)) => break,
+                // subscribers only listen
+                Some(Ok(_)) => {},
+            },
+        }
+    }
+    let _ = session.close(None).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_starts_at_offset_and_live_continues() {
+        let log = EventLog::new(100);
+        for n in 0..5 {
+            log.publish(EventKind::Register, &Worker::test(&n.to_string()));
+        }
+
+        let (replay, mut rx) = log.subscribe(Some(3));
+        assert_eq!(replay.iter().map(|e| e.offset).collect::<Vec<_>>(), vec![3, 4]);
+
+        log.publish(EventKind::Heartbeat, &Worker::test("0"));
+        let live = rx.try_recv().unwrap();
+        assert_eq!((live.offset, live.kind), (5, EventKind::Heartbeat));
+    }
+
+    #[test]
+    fn log_keeps_only_the_newest_events() {
+        let log = EventLog::new(3);
+        for n in 0..10 {
+            assert_eq!(log.publish(EventKind::Register, &Worker::test(&n.to_string())), n);
+        }
+        let (replay, _) = log.subscribe(Some(0));
+        assert_eq!(replay.iter().map(|e| e.offset).collect::<Vec<_>>(), vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn sse_frame_carries_offset_and_kind() {
+        let log = EventLog::new(10);
+        log.publish(EventKind::Timeout, &Worker::test("abc"));
+        let (replay, _) = log.subscribe(Some(0));
+        let frame = String::from_utf8(sse_frame(&replay[0]).to_vec()).unwrap();
+        assert!(frame.starts_with("id: 0\nevent: timeout\ndata: {"));
+        assert!(frame.ends_with("}\n\n"));
+    }
+}
@@ -0,0 +1,496 @@
+//! Fault injection, for seeing how the sieve fleet and the mesh cope with a degraded backend.
+//! Rules are matched against the route pattern ahead of the handlers and can add latency, answer
+//! with an error, reset the connection or dribble the request or response body through slowly. They're loaded
+//! from `FAULT_RULES` (or `FAULT_RULES_FILE`) at startup and can be replaced at runtime through
+//! `/admin/faults`, which needs the admin token (see [`crate::admin`]).
+
+use std::{future::{Ready, ready}, rc::Rc, sync::RwLock, time::Duration};
+
+use actix_web::{
+    Error, HttpMessage, HttpResponse,
+    body::{BoxBody, MessageBody},
+    dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+    error::PayloadError,
+    http::StatusCode,
+    web::{self, Bytes},
+};
+use futures::{StreamExt, future::LocalBoxFuture};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sieve_protocol::JitterDistribution;
+
+use crate::AppData;
+use crate::admin::Admin;
+use crate::error::ApiError;
+
+/// One fault rule. The first rule matching a request's route and method is the one applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultRule {
+    /// Route pattern as registered, e.g. `/register` or `/workers/{id}`. `*` matches every route
+    /// except the admin ones, so faults can always be switched off again, and the probe and metrics
+    /// ones, so a fault test doesn't get the pod restarted or blind its own dashboards.
+    pub route: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Latency>,
+    /// Percentage of matching requests answered with `error_status` without reaching the handler.
+    #[serde(default)]
+    pub error_percent: f64,
+    #[serde(default = "default_error_status")]
+    pub error_status: u16,
+    /// Percentage of matching requests whose connection is dropped instead of answered.
+    #[serde(default)]
+    pub reset_percent: f64,
+    /// Reads the request body slowly before the handler gets it, like a client on a poor link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slow_request: Option<SlowBody>,
+    /// Sends the response body slowly. Not meant for `/events`, whose body never ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slow_body: Option<SlowBody>,
+}
+
+/// Delay added before the handler runs, sampled the same way as the sieve's start jitter.
+/// Uniform samples need `max_ms`; exponential ones use `mean_ms` and are only capped when `max_ms`
+/// is given.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Latency {
+    pub distribution: JitterDistribution,
+    #[serde(default)]
+    pub min_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_ms: Option<u64>,
+    #[serde(default)]
+    pub mean_ms: u64,
+}
+
+/// Passes a body on `chunk_bytes` at a time with `delay_ms` between chunks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlowBody {
+    pub chunk_bytes: usize,
+    pub delay_ms: u64,
+}
+
+// routes `*` leaves alone - kubelet probes and scrapes shouldn't see injected faults
+const WILDCARD_EXEMPT_ROUTES: [&str; 4] = ["/livez", "/readyz", "/health", "/metrics"];
+
+fn default_error_status() -> u16 {
+    503
+}
+
+impl FaultRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.route != "*" && !self.route.starts_with('/') {
+            return Err(format!("route '{}' must be '*' or start with '/'", self.route));
+        }
+        for (name, pct) in [("error_percent", self.error_percent), ("reset_percent", self.reset_percent)] {
+            if !(0.0..=100.0).contains(&pct) {
+                return Err(format!("{} for route '{}' must be between 0 and 100, got {}", name, self.route, pct));
+            }
+        }
+        if self.error_percent + self.reset_percent > 100.0 {
+            return Err(format!("error_percent and reset_percent for route '{}' add up to more than 100", self.route));
+        }
+        if self.error_status != 429 && !(500..=599).contains(&self.error_status) {
+            return Err(format!("error_status for route '{}' must be 429 or 5xx, got {}", self.route, self.error_status));
+        }
+        if let Some(latency) = &self.latency {
+            latency.validate().map_err(|e| format!("latency for route '{}' {}", self.route, e))?;
+        }
+        for (name, slow) in [("slow_request", &self.slow_request), ("slow_body", &self.slow_body)] {
+            if slow.as_ref().is_some_and(|s| s.chunk_bytes == 0) {
+                return Err(format!("{} chunk_bytes for route '{}' must be above zero", name, self.route));
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, route: &str, method: &str) -> bool {
+        let route_matches = match self.route.as_str() {
+            "*" => !route.starts_with("/admin/") && !WILDCARD_EXEMPT_ROUTES.contains(&route),
+            r => r == route,
+        };
+        route_matches && self.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    /// Decides what happens to one request.
+    fn roll(&self) -> Plan {
+        let mut rng = rand::thread_rng();
+        let delay = self.latency.as_ref().map(|l| Duration::from_millis(l.sample_ms(&mut rng)));
+        let roll: f64 = rng.gen_range(0.0..100.0);
+        let outcome = if roll < self.reset_percent {
+            Outcome::Reset
+        } else if roll < self.reset_percent + self.error_percent {
+            // validate() keeps this in range
+            Outcome::Error(StatusCode::from_u16(self.error_status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE))
+        } else {
+            Outcome::Pass(self.slow_body.clone())
+        };
+        Plan { delay, slow_request: self.slow_request.clone(), outcome }
+    }
+}
+
+impl Latency {
+    fn validate(&self) -> Result<(), String> {
+        match (self.distribution, self.max_ms) {
+            (JitterDistribution::Uniform, None) => return Err(String::from("needs max_ms for a uniform distribution")),
+            (JitterDistribution::Exponential, _) if self.mean_ms == 0 => return Err(String::from("needs mean_ms above zero for an exponential distribution")),
+            (JitterDistribution::Exponential, Some(max_ms)) if max_ms < self.mean_ms => {
+                return Err(format!("has max_ms ({}) below mean_ms ({})", max_ms, self.mean_ms));
+            },
+            _ => {},
+        }
+        match self.max_ms {
+            Some(max_ms) if self.min_ms > max_ms => Err(format!("has min_ms ({}) larger than max_ms ({})", self.min_ms, max_ms)),
+            _ => Ok(()),
+        }
+    }
+
+    fn sample_ms(&self, rng: &mut impl Rng) -> u64 {
+        match self.distribution {
+            JitterDistribution::None => self.min_ms,
+            // validate() makes sure uniform rules have a max
+            JitterDistribution::Uniform => rng.gen_range(self.min_ms..=self.max_ms.unwrap_or(self.min_ms)),
+            JitterDistribution::Exponential => {
+                let u: f64 = rng.gen();
+                let sample = -(self.mean_ms as f64) * (1.0 - u).ln();
+                (sample as u64).min(self.max_ms.unwrap_or(u64::MAX))
+            }
+        }
+    }
+}
+
+struct Plan {
+    delay: Option<Duration>,
+    // only applied when the request reaches the handler
+    slow_request: Option<SlowBody>,
+    outcome: Outcome,
+}
+
+enum Outcome {
+    Pass(Option<SlowBody>),
+    Error(StatusCode),
+    Reset,
+}
+
+impl Outcome {
+    fn name(&self) -> &'static str {
+        match self {
+            Outcome::Pass(Some(_)) => "slow-body",
+            Outcome::Pass(None) => "none",
+            Outcome::Error(_) => "error",
+            Outcome::Reset => "reset",
+        }
+    }
+}
+
+/// The live rule set, shared between the middleware and the admin endpoint.
+pub struct Faults {
+    rules: RwLock<Vec<FaultRule>>,
+}
+
+impl Faults {
+    pub fn new(rules: Vec<FaultRule>) -> Faults {
+        Faults { rules: RwLock::new(rules) }
+    }
+
+    /// Reads rules as a JSON array from `FAULT_RULES`, or from the file named by
+    /// `FAULT_RULES_FILE`. With neither set, registrations get the 400-1000ms of simulated latency
+    /// they've always had - `FAULT_RULES=[]` turns that off.
+    pub fn from_env() -> anyhow::Result<Faults> {
+        let (source, json) = match (std::env::var("FAULT_RULES"), std::env::var("FAULT_RULES_FILE")) {
+            (Ok(val), _) => ("FAULT_RULES", val),
+            (Err(_), Ok(path)) => {
+                let val = std::fs::read_to_string(&path)
+                    .map_err(|e| anyhow::anyhow!("Unable to read FAULT_RULES_FILE '{}': {}", path, e))?;
+                ("FAULT_RULES_FILE", val)
+            },
+            (Err(_), Err(_)) => return Ok(Faults::new(default_rules())),
+        };
+        let rules: Vec<FaultRule> = serde_json::from_str(&json)
+            .map_err(|e| anyhow::anyhow!("Invalid value for {}: {}", source, e))?;
+        for rule in &rules {
+            rule.validate().map_err(|e| anyhow::anyhow!("Invalid value for {}: {}", source, e))?;
+        }
+        Ok(Faults::new(rules))
+    }
+
+    pub fn rules(&self) -> Vec<FaultRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, rules: Vec<FaultRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    fn matching(&self, route: &str, method: &str) -> Option<FaultRule> {
+        self.rules.read().unwrap().iter().find(|r| r.matches(route, method)).cloned()
+    }
+}
+
+fn default_rules() -> Vec<FaultRule> {
+    vec![FaultRule {
+        route: String::from("/register"),
+        method: None,
+        latency: Some(Latency { distribution: JitterDistribution::Uniform, min_ms: 400, max_ms: Some(1000), mean_ms: 0 }),
+        error_percent: 0.0,
+        error_status: default_error_status(),
+        reset_percent: 0.0,
+        slow_request: None,
+        slow_body: None,
+    }]
+}
+
+/// Middleware applying the rules in [`Faults`]. Wrap it inside the logger and metrics so injected
+/// faults show up there like real ones.
+pub struct FaultInjection {
+    faults: web::Data<Faults>,
+}
+
+impl FaultInjection {
+    pub fn new(faults: web::Data<Faults>) -> FaultInjection {
+        FaultInjection { faults }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for FaultInjection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = FaultInjectionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(FaultInjectionMiddleware { service: Rc::new(service), faults: self.faults.clone() }))
+    }
+}
+
+pub struct FaultInjectionMiddleware<S> {
+    service: Rc<S>,
+    faults: web::Data<Faults>,
+}
+
+impl<S, B> Service<ServiceRequest> for FaultInjectionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let route = req.match_pattern();
+        let rule = route.as_deref().and_then(|r| self.faults.matching(r, req.method().as_str()));
+        let (route, plan) = match (route, rule) {
+            (Some(route), Some(rule)) => (route, rule.roll()),
+            _ => return Box::pin(async move { Ok(service.call(req).await?.map_into_boxed_body()) }),
+        };
+
+        if let Some(data) = req.app_data::<web::Data<AppData>>() {
+            if plan.delay.is_some() {
+                data.metrics.injected_faults.with_label_values(&[&route, "latency"]).inc();
+            }
+            if plan.slow_request.is_some() && matches!(plan.outcome, Outcome::Pass(_)) {
+                data.metrics.injected_faults.with_label_values(&[&route, "slow-request"]).inc();
+            }
+            if !matches!(plan.outcome, Outcome::Pass(None)) {
+                data.metrics.injected_faults.with_label_values(&[&route, plan.outcome.name()]).inc();
+            }
+        }
+
+        Box::pin(async move {
+            if let Some(delay) = plan.delay {
+                tracing::debug!("Injecting {}ms of latency on {}", delay.as_millis(), route);
+                actix_web::rt::time::sleep(delay).await;
+            }
+            match plan.outcome {
+                Outcome::Error(status) => {
+                    tracing::debug!("Injecting a {} response on {}", status.as_u16(), route);
+                    Ok(req.error_response(ApiError::Injected(status)))
+                },
+                Outcome::Reset => {
+                    tracing::debug!("Injecting a connection reset on {}", route);
+                    // an erroring body makes actix drop the connection instead of finishing the response
+                    let body = futures::stream::once(async {
+                        Err::<Bytes, _>(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "injected connection reset"))
+                    });
+                    Ok(req.into_response(HttpResponse::Ok().streaming(body)))
+                },
+                Outcome::Pass(slow) => {
+                    let mut req = req;
+                    if let Some(slow_request) = plan.slow_request {
+                        let payload = req.take_payload();
+                        req.set_payload(Payload::Stream { payload: Box::pin(throttle_request(payload, slow_request)) });
+                    }
+                    let res = service.call(req).await?.map_into_boxed_body();
+                    Ok(match slow {
+                        Some(slow) => res.map_body(|_, body| BoxBody::new(actix_web::body::BodyStream::new(throttle(body, slow)))),
+                        None => res,
+                    })
+                },
+            }
+        })
+    }
+}
+
+enum Throttle {
+    Pending(BoxBody),
+    Draining(Bytes),
+}
+
+fn throttle(body: BoxBody, slow: SlowBody) -> impl futures::Stream<Item = Result<Bytes, Error>> {
+    futures::stream::unfold(Some(Throttle::Pending(body)), move |state| async move {
+        let mut rest = match state? {
+            Throttle::Pending(body) => match actix_web::body::to_bytes(body).await {
+                Ok(bytes) => bytes,
+                Err(e) => return Some((Err(actix_web::error::ErrorInternalServerError(e.to_string())), None)),
+            },
+            Throttle::Draining(rest) => {
+                actix_web::rt::time::sleep(Duration::from_millis(slow.delay_ms)).await;
+                rest
+            },
+        };
+        if rest.is_empty() {
+            return None;
+        }
+        let chunk = rest.split_to(slow.chunk_bytes.min(rest.len()));
+        let next = if rest.is_empty() { None } else { Some(Throttle::Draining(rest)) };
+        Some((Ok(chunk), next))
+    })
+}
+
+fn throttle_request(payload: Payload, slow: SlowBody) -> impl futures::Stream<Item = Result<Bytes, PayloadError>> {
+    futures::stream::unfold((payload, Bytes::new(), false), move |(mut payload, mut rest, started)| async move {
+        while rest.is_empty() {
+            match payload.next().await? {
+                Ok(bytes) => rest = bytes,
+                Err(e) => return Some((Err(e), (payload, rest, started))),
+            }
+        }
+        if started {
+            actix_web::rt::time::sleep(Duration::from_millis(slow.delay_ms)).await;
+        }
+        let chunk = rest.split_to(slow.chunk_bytes.min(rest.len()));
+        Some((Ok(chunk), (payload, rest, true)))
+    })
+}
+
+#[tracing::instrument(skip(faults))]
+pub async fn get_faults(_admin: Admin, faults: web::Data<Faults>) -> HttpResponse {
+    HttpResponse::Ok().json(faults.rules())
+}
+
+#[tracing::instrument(skip(faults))]
+pub async fn set_faults(_admin: Admin, faults: web::Data<Faults>, rules: web::Json<Vec<FaultRule>>) -> Result<HttpResponse, ApiError> {
+    for rule in rules.iter() {
+        rule.validate().map_err(ApiError::Validation)?;
+    }
+    tracing::warn!("Replacing fault rules with {:?}", rules);
+    faults.replace(rules.into_inner());
+    Ok(HttpResponse::Ok().json(faults.rules()))
+}
+
+#[tracing::instrument(skip(faults))]
+pub async fn clear_faults(_admin: Admin, faults: web::Data<Faults>) -> HttpResponse {
+    tracing::warn!("Clearing all fault rules");
+    faults.replace(Vec::new());
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule(value: serde_json::Value) -> FaultRule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn rules_are_validated() {
+        assert!(rule(json!({ "route": "/register", "error_percent": 10.0 })).validate().is_ok());
+        assert!(rule(json!({ "route": "register" })).validate().is_err());
+        assert!(rule(json!({ "route": "*", "error_percent": 101.0 })).validate().is_err());
+        assert!(rule(json!({ "route": "*", "error_percent": 60.0, "reset_percent": 50.0 })).validate().is_err());
+        assert!(rule(json!({ "route": "*", "error_status": 404 })).validate().is_err());
+        assert!(rule(json!({ "route": "*", "error_status": 429 })).validate().is_ok());
+        assert!(rule(json!({ "route": "*", "latency": { "distribution": "uniform", "min_ms": 5, "max_ms": 1 } })).validate().is_err());
+        assert!(rule(json!({ "route": "*", "latency": { "distribution": "uniform", "min_ms": 5 } })).validate().is_err());
+        assert!(rule(json!({ "route": "*", "latency": { "distribution": "exponential" } })).validate().is_err());
+        assert!(rule(json!({ "route": "*", "latency": { "distribution": "exponential", "mean_ms": 50, "max_ms": 10 } })).validate().is_err());
+        assert!(rule(json!({ "route": "*", "latency": { "distribution": "exponential", "mean_ms": 50 } })).validate().is_ok());
+        assert!(serde_json::from_value::<FaultRule>(json!({ "route": "*", "eror_percent": 1.0 })).is_err());
+    }
+
+    #[test]
+    fn uncapped_exponential_latency_is_not_zero() {
+        let latency = Latency { distribution: JitterDistribution::Exponential, min_ms: 0, max_ms: None, mean_ms: 100 };
+        let mut rng = rand::thread_rng();
+        let total: u64 = (0..1000).map(|_| latency.sample_ms(&mut rng)).sum();
+        assert!(total > 50_000, "{}", total);
+    }
+
+    #[test]
+    fn wildcard_skips_admin_and_probe_routes() {
+        let any = rule(json!({ "route": "*" }));
+        assert!(any.matches("/register", "POST"));
+        assert!(!any.matches("/admin/faults", "DELETE"));
+        for route in ["/livez", "/readyz", "/health", "/metrics"] {
+            assert!(!any.matches(route, "GET"), "{}", route);
+        }
+        assert!(rule(json!({ "route": "/metrics" })).matches("/metrics", "GET"));
+
+        let put_only = rule(json!({ "route": "/result", "method": "put" }));
+        assert!(put_only.matches("/result", "PUT"));
+        assert!(!put_only.matches("/result", "GET"));
+    }
+
+    #[actix_web::test]
+    async fn injected_errors_and_slow_bodies_reach_the_client() {
+        use actix_web::{App, test};
+
+        let faults = web::Data::new(Faults::new(vec![
+            rule(json!({ "route": "/fail", "error_percent": 100.0, "error_status": 429 })),
+            rule(json!({ "route": "/slow", "slow_body": { "chunk_bytes": 4, "delay_ms": 1 } })),
+            rule(json!({ "route": "/upload", "slow_request": { "chunk_bytes": 3, "delay_ms": 1 } })),
+        ]));
+        let app = test::init_service(App::new()
+            .app_data(faults.clone())
+            .app_data(web::Data::new(crate::admin::AdminToken::new(Some(String::from("s3cret")))))
+            .wrap(FaultInjection::new(faults.clone()))
+            .route("/fail", web::get().to(HttpResponse::Ok))
+            .route("/slow", web::get().to(|| async { "a slow response body" }))
+            .route("/upload", web::post().to(|body: Bytes| async move { body }))
+            .route("/admin/faults", web::get().to(get_faults))
+            .route("/admin/faults", web::delete().to(clear_faults))).await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/fail").to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/slow").to_request()).await;
+        assert_eq!(test::read_body(res).await, "a slow response body");
+
+        let req = test::TestRequest::post().uri("/upload").set_payload("a slow request body").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(test::read_body(res).await, "a slow request body");
+
+        let res = test::call_service(&app, test::TestRequest::delete().uri("/admin/faults").to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::delete().uri("/admin/faults").insert_header(("Authorization", "Bearer s3cret")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = test::call_service(&app, test::TestRequest::get().uri("/fail").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
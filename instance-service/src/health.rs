@@ -0,0 +1,226 @@
+//! Liveness and readiness. `/livez` only says the process is serving requests; `/readyz` also
+//! checks that the worker store answers and the background tasks are still ticking, so a replica
+//! that can't persist anything is taken out of rotation instead of failing every result.
+
+use std::{sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::AppData;
+
+// a store that takes longer than this to answer a ping is as good as down
+const STORE_PING_TIMEOUT: Duration = Duration::from_secs(2);
+// missed intervals before a background task counts as stuck
+const MISSED_TICKS: u64 = 3;
+
+/// Tracks one background loop. The task bumps it each time round and readiness compares the last
+/// tick against its interval.
+#[derive(Debug)]
+pub struct TaskMonitor {
+    name: &'static str,
+    // zero until the task has started
+    interval_ms: AtomicU64,
+    last_tick_ms: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+struct TaskStatus {
+    name: &'static str,
+    ok: bool,
+    started: bool,
+    interval_ms: u64,
+    ms_since_tick: Option<u64>,
+}
+
+impl TaskMonitor {
+    pub fn new(name: &'static str) -> TaskMonitor {
+        TaskMonitor {
+            name,
+            interval_ms: AtomicU64::new(0),
+            last_tick_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn start(&self, interval: Duration) {
+        self.interval_ms.store(interval.as_millis().max(1) as u64, Ordering::Relaxed);
+        self.tick();
+    }
+
+    pub fn tick(&self) {
+        self.last_tick_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    fn status(&self) -> TaskStatus {
+        let interval_ms = self.interval_ms.load(Ordering::Relaxed);
+        let started = interval_ms > 0;
+        let ms_since_tick = started.then(|| now_ms().saturating_sub(self.last_tick_ms.load(Ordering::Relaxed)));
+        TaskStatus {
+            name: self.name,
+            ok: ms_since_tick.is_some_and(|ms| ms <= interval_ms * MISSED_TICKS),
+            started,
+            interval_ms,
+            ms_since_tick,
+        }
+    }
+}
+
+/// Process-wide health state, held in `AppData`.
+#[derive(Debug)]
+pub struct Health {
+    started_at: DateTime<Utc>,
+    started: Instant,
+    pub reaper: TaskMonitor,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            started_at: Utc::now(),
+            started: Instant::now(),
+            reaper: TaskMonitor::new("reaper"),
+        }
+    }
+
+    fn tasks(&self) -> Vec<TaskStatus> {
+        vec![self.reaper.status()]
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Liveness {
+    status: &'static str,
+    started_at: DateTime<Utc>,
+    uptime_ms: u128,
+    workers: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct StoreStatus {
+    backend: &'static str,
+    ok: bool,
+    latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Readiness {
+    status: &'static str,
+    store: StoreStatus,
+    tasks: Vec<TaskStatus>,
+}
+
+#[tracing::instrument(skip(store))]
+pub async fn livez(store: web::Data<AppData>) -> HttpResponse {
+    HttpResponse::Ok().json(Liveness {
+        status: "alive",
+        started_at: store.health.started_at,
+        uptime_ms: store.health.started.elapsed().as_millis(),
+        workers: store.sieve_map.len(),
+    })
+}
+
+#[tracing::instrument(skip(store))]
+pub async fn readyz(store: web::Data<AppData>) -> HttpResponse {
+    let start = Instant::now();
+    let ping = match actix_web::rt::time::timeout(STORE_PING_TIMEOUT, store.store.ping()).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow::anyhow!("no answer within {}ms", STORE_PING_TIMEOUT.as_millis())),
+    };
+    store.metrics.observe_store(store.store.name(), "ping", start.elapsed(), ping.is_ok());
+    let store_status = StoreStatus {
+        backend: store.store.name(),
+        ok: ping.is_ok(),
+        latency_ms: start.elapsed().as_millis(),
+        error: ping.err().map(|e| e.to_string()),
+    };
+
+    let tasks = store.health.tasks();
+    let ready = store_status.ok && tasks.iter().all(|t| t.ok);
+    let body = Readiness {
+        status: if ready { "ready" } else { "not-ready" },
+        store: store_status,
+        tasks,
+    };
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        tracing::warn!("Reporting not ready: {:?}", body);
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use async_trait::async_trait;
+    use crate::{EventLog, Worker, events, store::WorkerStore};
+
+    struct DownStore;
+
+    #[async_trait]
+    impl WorkerStore for DownStore {
+        fn name(&self) -> &'static str {
+            "down"
+        }
+
+        async fn save(&self, _worker: &Worker) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("connection refused"))
+        }
+
+        async fn load_all(&self) -> anyhow::Result<Vec<Worker>> {
+            Err(anyhow::anyhow!("connection refused"))
+        }
+
+        async fn ping(&self) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("connection refused"))
+        }
+    }
+
+    #[actix_web::test]
+    async fn ready_needs_a_reachable_store_and_a_running_reaper() {
+        use actix_web::{App, http::StatusCode, test};
+
+        async fn ready(store: Arc<dyn WorkerStore>, start_reaper: bool) -> (StatusCode, serde_json::Value) {
+            let data = web::Data::new(AppData::new(store, EventLog::new(events::DEFAULT_CAPACITY)));
+            if start_reaper {
+                data.health.reaper.start(Duration::from_secs(5));
+            }
+            let app = test::init_service(App::new()
+                .app_data(data)
+                .route("/livez", web::get().to(livez))
+                .route("/readyz", web::get().to(readyz))).await;
+            assert_eq!(test::call_service(&app, test::TestRequest::get().uri("/livez").to_request()).await.status(), StatusCode::OK);
+            let res = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+            (res.status(), test::read_body_json(res).await)
+        }
+
+        let (status, body) = ready(Arc::new(crate::store::MemoryStore), true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+
+        let (status, body) = ready(Arc::new(crate::store::MemoryStore), false).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["tasks"][0]["started"], false);
+
+        let (status, body) = ready(Arc::new(DownStore), true).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["store"]["error"], "connection refused");
+    }
+
+    #[test]
+    fn stalled_task_is_not_ok() {
+        let monitor = TaskMonitor::new("reaper");
+        monitor.start(Duration::from_millis(10));
+        assert!(monitor.status().ok);
+        monitor.last_tick_ms.store(now_ms() - 1000, Ordering::Relaxed);
+        assert!(!monitor.status().ok);
+    }
+}
@@ -0,0 +1,253 @@
+//! Worker lifecycle and the reaper that times out workers which stop reporting.
+//!
+//! ```text
+//! Registered -> Running -> Completed | Partial | Failed | TimedOut
+//!      \___________________________^
+//! ```
+//!
+//! A registered worker can finish without ever sending a heartbeat (older sieves don't). TimedOut
+//! isn't final - a sieve that was only slow can still heartbeat or report, which moves it on.
+//! Completed, Partial and Failed are final.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppData, Worker};
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkerStatus {
+    Registered,
+    Running,
+    Completed,
+    Partial,
+    Failed,
+    TimedOut,
+}
+
+impl WorkerStatus {
+    pub const ALL: [WorkerStatus; 6] = [
+        WorkerStatus::Registered,
+        WorkerStatus::Running,
+        WorkerStatus::Completed,
+        WorkerStatus::Partial,
+        WorkerStatus::Failed,
+        WorkerStatus::TimedOut,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkerStatus::Registered => "registered",
+            WorkerStatus::Running => "running",
+            WorkerStatus::Completed => "completed",
+            WorkerStatus::Partial => "partial",
+            WorkerStatus::Failed => "failed",
+            WorkerStatus::TimedOut => "timed-out",
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(self, WorkerStatus::Completed | WorkerStatus::Partial | WorkerStatus::Failed)
+    }
+
+    pub fn can_become(&self, next: WorkerStatus) -> bool {
+        if self.is_final() {
+            return false;
+        }
+        // repeated heartbeats keep a worker running, but nothing goes back to registered and a
+        // worker can't time out twice
+        !matches!((self, next), (_, WorkerStatus::Registered) | (WorkerStatus::TimedOut, WorkerStatus::TimedOut))
+    }
+}
+
+impl FromStr for WorkerStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [WorkerStatus::Registered, WorkerStatus::Running, WorkerStatus::Completed, WorkerStatus::Partial, WorkerStatus::Failed, WorkerStatus::TimedOut]
+            .into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| format!("unknown worker status '{}'", s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransitionError {
+    pub from: WorkerStatus,
+    pub to: WorkerStatus,
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "worker is {} and cannot become {}", self.from.as_str(), self.to.as_str())
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+impl Worker {
+    /// Moves the worker to `next` if the lifecycle allows it.
+    pub fn transition(&mut self, next: WorkerStatus) -> Result<(), TransitionError> {
+        if !self.status.can_become(next) {
+            return Err(TransitionError { from: self.status, to: next });
+        }
+        self.status = next;
+        Ok(())
+    }
+}
+
+/// Deadlines after which the reaper gives up on a worker. A zero deadline disables that check.
+#[derive(Debug, Clone)]
+pub struct ReaperConfig {
+    pub interval: Duration,
+    // registered but never heard from again
+    pub register_timeout: Duration,
+    // running but no heartbeat for this long
+    pub heartbeat_timeout: Duration,
+}
+
+impl ReaperConfig {
+    pub fn from_env() -> anyhow::Result<ReaperConfig> {
+        Ok(ReaperConfig {
+            interval: Duration::from_millis(env_u64("REAPER_INTERVAL_MS", 5000)?.max(100)),
+            // generous by default - sieves without heartbeats stay registered for the whole kernel run
+            register_timeout: Duration::from_millis(env_u64("REGISTER_TIMEOUT_MS", 600000)?),
+            heartbeat_timeout: Duration::from_millis(env_u64("HEARTBEAT_TIMEOUT_MS", 60000)?),
+        })
+    }
+
+    pub fn is_expired(&self, worker: &Worker, now: DateTime<Utc>) -> bool {
+        let (since, timeout) = match worker.status {
+            WorkerStatus::Registered => (worker.last_seen.or(worker.registered_at), self.register_timeout),
+            WorkerStatus::Running => (worker.last_seen, self.heartbeat_timeout),
+            _ => return false,
+        };
+        if timeout.is_zero() {
+            return false;
+        }
+        match (since, chrono::Duration::from_std(timeout)) {
+            (Some(since), Ok(timeout)) => now - since > timeout,
+            _ => false,
+        }
+    }
+}
+
+/// Times out every worker past its deadline and returns their IDs.
+pub async fn reap(store: &AppData, cfg: &ReaperConfig) -> Vec<String> {
+    let now = Utc::now();
+    let expired: Vec<String> = store.sieve_map.iter()
+        .filter(|w| cfg.is_expired(w.value(), now))
+        .map(|w| w.key().clone())
+        .collect();
+
+    let mut reaped = Vec::with_capacity(expired.len());
+    for id in expired {
+        // re-checked under the entry lock, a heartbeat may have landed since the scan
+        let timed_out = match store.sieve_map.get_mut(&id) {
+            Some(mut w) if cfg.is_expired(&w, now) => w.transition(WorkerStatus::TimedOut).is_ok(),
+            _ => false,
+        };
+        if !timed_out {
+            continue;
+        }
+        tracing::warn!("Worker {} timed out", id);
+        if let Err(e) = store.record(crate::EventKind::Timeout, &id).await {
+            tracing::error!("Failed to persist timeout for worker {}: {}", id, e);
+        }
+        reaped.push(id);
+    }
+    reaped
+}
+
+pub async fn run_reaper(store: actix_web::web::Data<AppData>, cfg: ReaperConfig) {
+    tracing::info!("Starting worker reaper - register timeout {:?}, heartbeat timeout {:?}", cfg.register_timeout, cfg.heartbeat_timeout);
+    store.health.reaper.start(cfg.interval);
+    let mut ticker = actix_web::rt::time::interval(cfg.interval);
+    loop {
+        ticker.tick().await;
+        store.health.reaper.tick();
+        let reaped = reap(&store, &cfg).await;
+        if !reaped.is_empty() {
+            tracing::info!("Reaper timed out {} workers", reaped.len());
+        }
+    }
+}
+
+fn env_u64(name: &str, default: u64) -> anyhow::Result<u64> {
+    match std::env::var(name) {
+        Ok(val) => val.parse::<u64>()
+            .map_err(|e| anyhow::anyhow!("Invalid value '{}' for {}: {}", val, name, e)),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn worker(status: WorkerStatus, registered_at: DateTime<Utc>, last_seen: Option<DateTime<Utc>>) -> Worker {
+        Worker { status, registered_at: Some(registered_at), last_seen, ..Worker::test("abc") }
+    }
+
+    #[test]
+    fn final_states_accept_nothing() {
+        use WorkerStatus::*;
+        let all = [Registered, Running, Completed, Partial, Failed, TimedOut];
+        for from in [Completed, Partial, Failed] {
+            assert!(from.is_final());
+            assert!(all.iter().all(|to| !from.can_become(*to)), "{:?} should be final", from);
+        }
+        assert!(!Running.can_become(Registered));
+        assert!(!TimedOut.can_become(TimedOut));
+        assert!(TimedOut.can_become(Completed));
+        assert!(Registered.can_become(Completed));
+    }
+
+    #[test]
+    fn transition_reports_both_states() {
+        let mut w = worker(WorkerStatus::Completed, Utc::now(), None);
+        let err = w.transition(WorkerStatus::Running).unwrap_err();
+        assert_eq!(err.to_string(), "worker is completed and cannot become running");
+        assert_eq!(w.status, WorkerStatus::Completed);
+    }
+
+    #[test]
+    fn status_strings_round_trip() {
+        for s in ["registered", "running", "completed", "partial", "failed", "timed-out"] {
+            let status: WorkerStatus = s.parse().unwrap();
+            assert_eq!(status.as_str(), s);
+            assert_eq!(serde_json::to_value(status).unwrap(), serde_json::json!(s));
+        }
+    }
+
+    #[actix_web::test]
+    async fn reaper_times_out_silent_workers_only() {
+        let cfg = ReaperConfig {
+            interval: Duration::from_secs(1),
+            register_timeout: Duration::from_secs(60),
+            heartbeat_timeout: Duration::from_secs(10),
+        };
+        let now = Utc::now();
+        let store = AppData::new(Arc::new(crate::store::MemoryStore), crate::EventLog::new(10));
+        let workers = [
+            ("quiet-registered", worker(WorkerStatus::Registered, now - chrono::Duration::seconds(120), None)),
+            ("fresh-registered", worker(WorkerStatus::Registered, now - chrono::Duration::seconds(5), None)),
+            ("stale-running", worker(WorkerStatus::Running, now - chrono::Duration::seconds(120), Some(now - chrono::Duration::seconds(30)))),
+            ("live-running", worker(WorkerStatus::Running, now - chrono::Duration::seconds(120), Some(now - chrono::Duration::seconds(2)))),
+            ("done", worker(WorkerStatus::Completed, now - chrono::Duration::seconds(600), None)),
+        ];
+        for (id, mut w) in workers {
+            w.id = String::from(id);
+            store.sieve_map.insert(String::from(id), w);
+        }
+
+        let mut reaped = reap(&store, &cfg).await;
+        reaped.sort();
+        assert_eq!(reaped, vec!["quiet-registered", "stale-running"]);
+        assert_eq!(store.sieve_map.get("stale-running").unwrap().status, WorkerStatus::TimedOut);
+        assert_eq!(store.sieve_map.get("live-running").unwrap().status, WorkerStatus::Running);
+    }
+}
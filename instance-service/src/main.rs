@@ -1,140 +1,693 @@
-use std::{collections::HashMap, sync::Mutex, thread::sleep, time::Duration};
+use std::{sync::Arc, time::Instant};
 
-use actix_web::{App, HttpResponse, HttpServer, web};
-use rand::Rng;
-use redis::Commands;
+use actix_web::{App, HttpResponse, HttpServer, dev::Service, web};
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, mapref::entry::Entry};
 use serde::{Deserialize, Serialize};
+use sieve_protocol::integrity;
+use sieve_protocol::{FailurePayload, HeartbeatPayload, KernelSummary, ProbeSummary, RegisterPayload, RegisterResponse, ResultPayload, StartInfo, OLDEST_SIEVE_VERSION};
 use tracing_actix_web::TracingLogger;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Sieve {
-    id: String,
-}
+use error::ApiError;
+use events::{EventKind, EventLog};
+use lifecycle::WorkerStatus;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct SieveResult {
-    id: String,
-    primes: Vec<i32>,
-}
+mod admin;
+mod error;
+mod events;
+mod faults;
+mod health;
+mod lifecycle;
+mod metrics;
+mod query;
+mod redis_config;
+mod runs;
+mod server;
+mod stats;
+mod store;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Hash, Clone)]
 struct Worker {
     id: String,
+    protocol_version: u32,
+    status: WorkerStatus,
+    // None when the result arrived from a worker that never registered
+    registered_at: Option<DateTime<Utc>>,
+    completed_at: Option<DateTime<Utc>>,
+    // last register, heartbeat or report - what the reaper measures silence from
+    last_seen: Option<DateTime<Utc>>,
+    // reason the sieve gave when it reported a failure
+    failure: Option<String>,
+    start: Option<StartInfo>,
     results: Option<PrimeResult>,
+    probe: Option<ProbeSummary>,
+    kernel: Option<KernelSummary>,
+    // digest of the accepted primes - a resubmission is only a duplicate if it matches
+    #[serde(default)]
+    result_digest: Option<String>,
+    // every PUT /result seen for this worker, accepted or not
+    #[serde(default)]
+    result_attempts: u32,
+}
+
+#[cfg(test)]
+impl Worker {
+    /// A freshly registered worker with nothing else set, for tests to adjust.
+    fn test(id: &str) -> Worker {
+        Worker {
+            id: String::from(id),
+            protocol_version: 3,
+            status: WorkerStatus::Registered,
+            registered_at: None,
+            completed_at: None,
+            last_seen: None,
+            failure: None,
+            start: None,
+            results: None,
+            probe: None,
+            kernel: None,
+            result_digest: None,
+            result_attempts: 0,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Hash, Clone)]
 struct PrimeResult {
-    quantity: usize,
-    max_prime: i32,
+    quantity: u64,
+    max_prime: u64,
 }
 
-#[derive(Debug, Clone)]
+impl PrimeResult {
+    /// Summarizes a sieve's primes. Sieves send primes in ascending order so the last one is the
+    /// largest. Returns `None` for an empty list.
+    fn from_primes(primes: &[u64]) -> Option<PrimeResult> {
+        primes.last().map(|max| PrimeResult {
+            quantity: primes.len() as u64,
+            max_prime: *max,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EchoParams {
+    size: Option<usize>,
+}
+
+// caps both the accepted echo body and the requested response size
+const MAX_ECHO_BYTES: usize = 16 * 1024 * 1024;
+
+/// Shared handler state. The map is sharded so concurrent requests only contend when they land on
+/// the same shard, and no lock is ever held across an await.
 struct AppData {
-    sieve_map: HashMap<String, Worker>,
-    redis: redis::Client,
+    sieve_map: DashMap<String, Worker>,
+    store: Arc<dyn store::WorkerStore>,
+    events: EventLog,
+    metrics: metrics::Metrics,
+    health: health::Health,
+}
+
+impl AppData {
+    fn new(store: Arc<dyn store::WorkerStore>, events: EventLog) -> AppData {
+        AppData {
+            sieve_map: DashMap::new(),
+            store,
+            events,
+            metrics: metrics::Metrics::new(),
+            health: health::Health::new(),
+        }
+    }
+
+    /// Publishes the change to `id` on the event feed and writes the record through to the
+    /// backing store.
+    async fn record(&self, kind: EventKind, id: &str) -> anyhow::Result<()> {
+        // clone out of the map so the shard lock isn't held across the await
+        let worker = self.sieve_map.get(id).map(|w| w.value().clone());
+        match worker {
+            Some(worker) => {
+                self.events.publish(kind, &worker);
+                let start = Instant::now();
+                let saved = self.store.save(&worker).await;
+                self.metrics.observe_store(self.store.name(), "save", start.elapsed(), saved.is_ok());
+                saved
+            },
+            None => Ok(()),
+        }
+    }
 }
 
+/// Shared key used to check result signatures. Results are only required to be signed when a key
+/// is configured.
+#[derive(Clone)]
+struct ResultVerifier {
+    key: Option<Vec<u8>>,
+}
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
     // init tracing logging
     tracing_subscriber::fmt::init();
 
-    // init datastore for instance service
-    let hmap: HashMap<String, Worker> = HashMap::new();
-    tracing::debug!("Completed hashmap creation for storing results.");
+    let worker_store = store::store_from_env().await?;
+    let event_capacity = match std::env::var("EVENT_BUFFER") {
+        Ok(val) => val.parse::<usize>().map_err(|e| anyhow::anyhow!("Invalid value '{}' for EVENT_BUFFER: {}", val, e))?,
+        Err(_) => events::DEFAULT_CAPACITY,
+    };
+    let store = web::Data::new(AppData::new(worker_store.clone(), EventLog::new(event_capacity)));
+    tracing::info!("Built AppData object with a concurrent map for local storage and '{}' store for durable data", worker_store.name());
 
-    // build redis client and wrap it for use as data
-    let redis_url = std::env::var("REDIS_URL")?;
-    let redis_port = std::env::var("REDIS_PORT")?;
-    //let redis_db = std::env::var("REDIS_DB")?;
-    //let formatted_conn_string = format!("redis://{}:{}/{}", redis_url, redis_port, redis_db);
-    let formatted_conn_string = format!("redis://{}:{}/", redis_url, redis_port);
-    tracing::debug!("Built formatted connection string for Redis - {}", formatted_conn_string);
+    // pick up anything recorded before a restart
+    let start = Instant::now();
+    let stored = worker_store.load_all().await;
+    store.metrics.observe_store(worker_store.name(), "load_all", start.elapsed(), stored.is_ok());
+    let stored = stored
+        .map_err(|e| anyhow::anyhow!("Unable to load stored workers from '{}' store: {}", worker_store.name(), e))?;
+    tracing::info!("Rehydrated {} workers from '{}' store", stored.len(), worker_store.name());
+    for worker in stored {
+        store.sieve_map.insert(worker.id.clone(), worker);
+    }
 
-    let client = redis::Client::open(formatted_conn_string.as_str())?;
+    let hmac_key = integrity::key_from_env()
+        .map_err(|e| anyhow::anyhow!("Unable to load result signing key: {}", e))?;
+    match hmac_key {
+        Some(_) => tracing::info!("RESULT_HMAC_KEY configured - unsigned results will be rejected."),
+        None => tracing::warn!("No RESULT_HMAC_KEY configured - accepting unsigned results."),
+    }
+    let verifier = web::Data::new(ResultVerifier { key: hmac_key });
 
-    let store = web::Data::new(Mutex::new(AppData {
-        sieve_map: hmap,
-        redis: client,
-    }));
-    tracing::info!("Build AppData object with HashMap for local storage and Redis client for remote data");
+    let reaper = lifecycle::ReaperConfig::from_env()?;
+    actix_web::rt::spawn(lifecycle::run_reaper(store.clone(), reaper));
 
+    let server_config = server::ServerConfig::from_env()?;
+    let faults = web::Data::new(faults::Faults::from_env()?);
+    let admin_token = web::Data::new(admin::AdminToken::from_env()?);
+    if !admin_token.enabled() {
+        tracing::warn!("No ADMIN_TOKEN configured - the /admin routes are disabled.");
+    }
+    for rule in faults.rules() {
+        tracing::info!("Fault rule active: {:?}", rule);
+    }
 
-    HttpServer::new(move || {
+    let max_payload_bytes = server_config.max_payload_bytes;
+    let mut server = HttpServer::new(move || {
     App::new()
         .app_data(store.clone())
+        .app_data(verifier.clone())
+        .app_data(faults.clone())
+        .app_data(admin_token.clone())
+        // innermost, so injected faults are logged and measured like real ones
+        .wrap(faults::FaultInjection::new(faults.clone()))
         // logging
         .wrap(TracingLogger::default())
-        .route("/register", web::post().to(register_sieve))
-        .route("/result", web::put().to(save_result))
-        .route("/health", web::get().to(health_check))
-    })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await?;
+        // request metrics, labelled by route pattern so worker IDs don't blow up the label set
+        .wrap_fn({
+            let store = store.clone();
+            move |req, srv| {
+                let store = store.clone();
+                let route = req.match_pattern().unwrap_or_else(|| String::from("unmatched"));
+                let method = req.method().to_string();
+                let body_bytes = req.headers().get("content-length")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok());
+                let start = Instant::now();
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await?;
+                    store.metrics.observe_request(&method, &route, res.status().as_u16(), start.elapsed(), body_bytes);
+                    Ok(res)
+                }
+            }
+        })
+        .configure(routes(max_payload_bytes))
+    });
+    if let Some(workers) = server_config.workers {
+        server = server.workers(workers);
+    }
+    if let Some(keep_alive) = server_config.keep_alive() {
+        server = server.keep_alive(keep_alive);
+    }
+    server = match &server_config.tls {
+        Some(tls) => {
+            let rustls_config = tls.load()?;
+            tracing::info!("Serving HTTPS on {}:{} with certificate '{}'{}", server_config.bind_address, server_config.port, tls.cert_path,
+                if tls.client_ca_path.is_some() { ", requiring client certificates" } else { "" });
+            server.bind_rustls(server_config.address(), rustls_config)?
+        },
+        None => {
+            tracing::info!("Serving plaintext HTTP on {}:{}", server_config.bind_address, server_config.port);
+            server.bind(server_config.address())?
+        },
+    };
+    server.run().await?;
 
     Ok(())
 }
 
+fn routes(max_payload_bytes: usize) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(web::JsonConfig::default().limit(max_payload_bytes).error_handler(error::json_error))
+            .app_data(web::QueryConfig::default().error_handler(error::query_error))
+            .app_data(web::PathConfig::default().error_handler(error::path_error))
+            .route("/register", web::post().to(register_sieve))
+            .route("/result", web::put().to(save_result))
+            .route("/heartbeat", web::post().to(heartbeat))
+            .route("/failure", web::post().to(report_failure))
+            // /health predates the split and stays as an alias for liveness
+            .route("/health", web::get().to(health::livez))
+            .route("/livez", web::get().to(health::livez))
+            .route("/readyz", web::get().to(health::readyz))
+            .route("/stats", web::get().to(stats::run_stats))
+            .route("/metrics", web::get().to(metrics::serve_metrics))
+            .route("/events", web::get().to(events::stream_events))
+            // admin handlers take an admin::Admin, so they answer 401 without the ADMIN_TOKEN bearer
+            // token and 403 when no token is configured
+            .route("/admin/faults", web::get().to(faults::get_faults))
+            .route("/admin/faults", web::put().to(faults::set_faults))
+            .route("/admin/faults", web::delete().to(faults::clear_faults))
+            .route("/admin/runs", web::get().to(runs::list_runs))
+            .route("/admin/runs/{run_id}", web::delete().to(runs::purge_run))
+            .route("/workers", web::get().to(query::list_workers))
+            .route("/workers/{id}", web::get().to(query::get_worker))
+            .service(web::resource("/echo")
+                .app_data(web::PayloadConfig::new(MAX_ECHO_BYTES))
+                .route(web::post().to(echo)));
+    }
+}
+
 #[tracing::instrument(skip(store))]
-async fn register_sieve(store: web::Data<Mutex<AppData>>, sieve: web::Json<Sieve>) -> HttpResponse {
-    let worker = Worker { id: sieve.id.clone(), results: None };
+async fn register_sieve(store: web::Data<AppData>, sieve: web::Json<RegisterPayload>) -> Result<HttpResponse, ApiError> {
+    error::validate_id(&sieve.id)?;
+    let protocol_version = sieve_protocol::negotiate(sieve.protocol_version, OLDEST_SIEVE_VERSION)
+        .inspect_err(|e| tracing::warn!("Rejecting registration from worker {}: {}", sieve.id, e))?;
+
+    let now = Utc::now();
+    let worker = Worker {
+        id: sieve.id.clone(),
+        protocol_version,
+        status: WorkerStatus::Registered,
+        registered_at: Some(now),
+        completed_at: None,
+        last_seen: Some(now),
+        failure: None,
+        start: sieve.start.clone(),
+        results: None,
+        probe: None,
+        kernel: None,
+        result_digest: None,
+        result_attempts: 0,
+    };
     let id = sieve.id.clone();
+    if let Some(start) = &sieve.start {
+        tracing::info!("Worker {} started at {} after {}ms {:?} jitter and {}ms stagger",
+            id, start.started_at.to_rfc3339(), start.jitter_ms, start.distribution, start.stagger_ms);
+    }
 
-    let mut hstore = store.try_lock().unwrap();
-    let hmap = &mut hstore.sieve_map;
-        
     tracing::info!("Inserting ID '{}' and worker {:?} into hstore", id, worker);
-    hmap.insert(id, worker);
-    let dur = rand::thread_rng().gen_range(400..=1000);
-    sleep(Duration::from_millis(dur));
+    // a result can beat its own registration here, so keep anything already recorded
+    store.sieve_map.entry(id.clone())
+        .and_modify(|wo| {
+            wo.protocol_version = worker.protocol_version;
+            wo.registered_at = worker.registered_at;
+            wo.start = worker.start.clone();
+        })
+        .or_insert(worker);
+    store.metrics.registrations.inc();
+
+    // the registration stands even if it can't be persisted, it just won't survive a restart
+    if let Err(e) = store.record(EventKind::Register, &id).await {
+        tracing::error!("Failed to persist registration for worker {}: {}", id, e);
+    }
 
-    HttpResponse::Created().finish()
+    Ok(HttpResponse::Created().json(RegisterResponse { protocol_version }))
 }
 
-#[tracing::instrument(skip(payload, store))]
-async fn save_result(store: web::Data<Mutex<AppData>>, payload: web::Json<SieveResult>) -> HttpResponse {
-    let mut hstore = store.try_lock().unwrap();
-    let hmap = &mut hstore.sieve_map;
+#[tracing::instrument(skip(payload, store, verifier))]
+async fn save_result(store: web::Data<AppData>, verifier: web::Data<ResultVerifier>, payload: web::Json<ResultPayload>) -> Result<HttpResponse, ApiError> {
+    let res = accept_result(&store, &verifier, &payload).await;
+    if let Err(e) = &res {
+        tracing::warn!("Rejecting result from worker {}: {}", payload.id, e);
+        store.metrics.reject_result(e.kind());
+    }
+    res
+}
+
+async fn accept_result(store: &AppData, verifier: &ResultVerifier, payload: &ResultPayload) -> Result<HttpResponse, ApiError> {
+    error::validate_id(&payload.id)?;
+    // attempts are counted before anything can reject them so retry storms show up per worker
+    store.metrics.result_attempts.inc();
+    if let Some(mut wo) = store.sieve_map.get_mut(&payload.id) {
+        wo.result_attempts += 1;
+    }
+    sieve_protocol::negotiate(payload.protocol_version, OLDEST_SIEVE_VERSION)?;
+    integrity::verify(payload, verifier.key.as_deref())?;
+    error::validate_primes(&payload.primes)?;
 
     tracing::info!("Received result from worker {} with primes length {}", &payload.id, &payload.primes.len());
-    let prime_res = PrimeResult {
-        max_prime: payload.primes.get(payload.primes.len() - 1).unwrap().clone(),
-        quantity: payload.primes.len()
-    };
+    if let Some(kernel) = &payload.kernel {
+        tracing::info!("Worker {} ran the {:?} kernel on input {} in {}us ({} operations)",
+            &payload.id, kernel.kernel, kernel.input, kernel.elapsed_micros, kernel.operations);
+    }
+    if let Some(probe) = &payload.probe {
+        tracing::info!("Worker {} latency probe: {} requests, p50 {}us, p90 {}us, p99 {}us, {} req/s, {} failures",
+            &payload.id, probe.requests, probe.p50_micros, probe.p90_micros, probe.p99_micros, probe.requests_per_sec, probe.failures);
+    }
+    let prime_res = PrimeResult::from_primes(&payload.primes)
+        .ok_or_else(|| ApiError::Validation(String::from("primes must not be empty")))?;
 
-    match hmap.get(&payload.id) {
-        Some(_) => {
+    let digest = integrity::digest_primes(&payload.primes);
+    let completed_at = Utc::now();
+    let next = if payload.partial { WorkerStatus::Partial } else { WorkerStatus::Completed };
+    // the entry guard holds the shard lock, so it's dropped before anything is awaited - and
+    // holding it from lookup to insert keeps two results for an unknown worker from racing
+    match store.sieve_map.entry(payload.id.clone()) {
+        Entry::Occupied(entry) if entry.get().result_digest.is_some() => {
+            // a retry of a result we already hold is acknowledged without touching the record,
+            // anything else would overwrite it
+            let wo = entry.get();
+            if wo.result_digest.as_deref() != Some(digest.as_str()) || wo.status != next {
+                return Err(ApiError::ResultConflict(format!("worker '{}' already submitted a different {} result", wo.id, wo.status.as_str())));
+            }
+            tracing::info!("Worker {} resubmitted its result (attempt {})", wo.id, wo.result_attempts);
+            drop(entry);
+            store.metrics.duplicate_results.inc();
+            store.record(EventKind::Duplicate, &payload.id).await?;
+            return Ok(HttpResponse::Ok().finish());
+        },
+        Entry::Occupied(entry) => {
+            let mut wo = entry.into_ref();
+            if wo.status == WorkerStatus::TimedOut {
+                tracing::info!("Worker {} reported results after timing out", payload.id);
+            }
+            wo.transition(next)?;
             tracing::debug!("Updating results for worker record and saving to store");
-            hmap.entry(payload.id.clone()).and_modify(|wo| { wo.results = Some(prime_res.clone()) });
-            
-            // commit the max value to redis as well
-            let redis = &hstore.redis;
-            let mut con = redis.get_connection().unwrap();
-            let _:() = con.set(payload.id.clone(), prime_res.max_prime.clone()).unwrap();
+            wo.completed_at = Some(completed_at);
+            wo.last_seen = Some(completed_at);
+            wo.results = Some(prime_res.clone());
+            wo.probe = payload.probe.clone();
+            wo.kernel = payload.kernel.clone();
+            wo.result_digest = Some(digest);
         },
-        None => {
+        Entry::Vacant(entry) => {
             tracing::warn!("Received results payload from worker {} that was not previously registered.", payload.id);
+            store.metrics.unregistered_results.inc();
             let worker = Worker {
                 id: payload.id.clone(),
-                results: Some(prime_res.clone())
+                protocol_version: payload.protocol_version,
+                status: next,
+                registered_at: None,
+                completed_at: Some(completed_at),
+                last_seen: Some(completed_at),
+                failure: None,
+                start: None,
+                results: Some(prime_res.clone()),
+                probe: payload.probe.clone(),
+                kernel: payload.kernel.clone(),
+                result_digest: Some(digest),
+                result_attempts: 1,
             };
-            hmap.insert(payload.id.clone(), worker);
-            
-            // commit the max value to redis as well
-            let redis = &hstore.redis;
-            let mut con = redis.get_connection().unwrap();
-            let _:() = con.set(payload.id.clone(), prime_res.max_prime.clone()).unwrap();
+            entry.insert(worker);
+        },
+    }
+
+    // commit the full record to the backing store as well - reporting failure lets the sieve retry
+    store.record(EventKind::Result, &payload.id).await?;
+
+    store.metrics.results.inc();
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(skip(store))]
+async fn heartbeat(store: web::Data<AppData>, payload: web::Json<HeartbeatPayload>) -> Result<HttpResponse, ApiError> {
+    error::validate_id(&payload.id)?;
+    sieve_protocol::negotiate(payload.protocol_version, OLDEST_SIEVE_VERSION)?;
+
+    match store.sieve_map.get_mut(&payload.id) {
+        Some(mut wo) => {
+            if wo.status == WorkerStatus::TimedOut {
+                tracing::info!("Worker {} was timed out but is still alive", payload.id);
+            }
+            wo.transition(WorkerStatus::Running)
+                .inspect_err(|e| tracing::debug!("Ignoring heartbeat from worker {}: {}", payload.id, e))?;
+            wo.last_seen = Some(Utc::now());
         },
+        None => return Err(unknown_worker(&payload.id)),
     }
 
-    HttpResponse::Ok().finish()
+    if let Err(e) = store.record(EventKind::Heartbeat, &payload.id).await {
+        tracing::error!("Failed to persist heartbeat for worker {}: {}", payload.id, e);
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(skip(store))]
+async fn report_failure(store: web::Data<AppData>, payload: web::Json<FailurePayload>) -> Result<HttpResponse, ApiError> {
+    error::validate_id(&payload.id)?;
+    sieve_protocol::negotiate(payload.protocol_version, OLDEST_SIEVE_VERSION)?;
+    if payload.reason.len() > error::MAX_REASON_LEN {
+        return Err(ApiError::Validation(format!("reason is {} bytes, the limit is {}", payload.reason.len(), error::MAX_REASON_LEN)));
+    }
+
+    match store.sieve_map.get_mut(&payload.id) {
+        Some(mut wo) => {
+            wo.transition(WorkerStatus::Failed)
+                .inspect_err(|e| tracing::warn!("Rejecting failure report from worker {}: {}", payload.id, e))?;
+            tracing::warn!("Worker {} failed: {}", payload.id, payload.reason);
+            wo.last_seen = Some(Utc::now());
+            wo.failure = Some(payload.reason.clone());
+        },
+        None => return Err(unknown_worker(&payload.id)),
+    }
+
+    store.record(EventKind::Failure, &payload.id).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+fn unknown_worker(id: &str) -> ApiError {
+    ApiError::NotFound(format!("no worker with ID '{}'", id))
+}
+
+#[tracing::instrument(skip(body))]
+async fn echo(params: web::Query<EchoParams>, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+    // with no size requested the body is reflected back as-is, otherwise a filler body of that size is returned
+    match params.size {
+        Some(size) if size > MAX_ECHO_BYTES => {
+            tracing::debug!("Rejecting echo request for {} bytes - above limit of {}", size, MAX_ECHO_BYTES);
+            Err(ApiError::Validation(format!("requested {} bytes, the limit is {}", size, MAX_ECHO_BYTES)))
+        },
+        Some(size) => {
+            Ok(HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .body(vec![b'e'; size]))
+        },
+        None => {
+            Ok(HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .body(body))
+        }
+    }
 }
 
-#[tracing::instrument]
-async fn health_check() -> HttpResponse {
-    tracing::info!("Responding to health check request with OK response.");
-    HttpResponse::Ok().finish()
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // the first primes past 2^32, as produced by a segmented sieve over 2^32..=2^32+200
+    const PRIMES_ABOVE_U32: [u64; 8] = [4294967311, 4294967357, 4294967371, 4294967377, 4294967387, 4294967389, 4294967459, 4294967477];
+
+    #[test]
+    fn sieve_result_accepts_primes_above_u32() {
+        let body = serde_json::json!({
+            "protocol_version": 2,
+            "id": "5d0a6a5e-0d6f-4b8e-9d5c-1c0f6f0c2a11",
+            "primes": PRIMES_ABOVE_U32,
+            "kernel": {
+                "kernel": "sieve",
+                "input": 4294967496u64,
+                "range_start": 4294967296u64,
+                "operations": 200,
+                "elapsed_micros": 1200,
+                "memory_bytes": 262144
+            }
+        });
+
+        let payload: ResultPayload = serde_json::from_value(body).unwrap();
+        assert_eq!(payload.primes, PRIMES_ABOVE_U32.to_vec());
+        assert_eq!(payload.kernel.unwrap().range_start, Some(1u64 << 32));
+    }
+
+    #[test]
+    fn prime_result_keeps_full_u64_max() {
+        let res = PrimeResult::from_primes(&PRIMES_ABOVE_U32).unwrap();
+        assert_eq!(res.max_prime, 4294967477);
+        assert_eq!(res.quantity, 8);
+
+        // what gets written to redis must not be truncated either
+        let args = redis::ToRedisArgs::to_redis_args(&res.max_prime);
+        assert_eq!(args, vec![b"4294967477".to_vec()]);
+    }
+
+    #[test]
+    fn prime_result_rejects_empty_primes() {
+        assert_eq!(PrimeResult::from_primes(&[]), None);
+    }
+
+    #[actix_web::test]
+    async fn heartbeat_moves_worker_to_running_until_it_finishes() {
+        use actix_web::{http::StatusCode, test};
+
+        let store = web::Data::new(AppData::new(Arc::new(store::MemoryStore), EventLog::new(events::DEFAULT_CAPACITY)));
+        let app = test::init_service(App::new()
+            .app_data(store.clone())
+            .app_data(web::Data::new(ResultVerifier { key: None }))
+            .configure(routes(server::DEFAULT_MAX_PAYLOAD_BYTES))).await;
+        let heartbeat = || test::TestRequest::post()
+            .uri("/heartbeat")
+            .set_json(json!({ "protocol_version": 3, "id": "abc" }));
+
+        assert_eq!(test::call_service(&app, heartbeat().to_request()).await.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::post().uri("/register").set_json(json!({ "protocol_version": 3, "id": "abc" }));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::CREATED);
+        assert_eq!(test::call_service(&app, heartbeat().to_request()).await.status(), StatusCode::OK);
+        assert_eq!(store.sieve_map.get("abc").unwrap().status, WorkerStatus::Running);
+
+        let req = test::TestRequest::put().uri("/result").set_json(json!({ "protocol_version": 3, "id": "abc", "primes": [2, 3], "partial": true }));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::OK);
+        assert_eq!(store.sieve_map.get("abc").unwrap().status, WorkerStatus::Partial);
+
+        // finished workers can't be revived or failed afterwards
+        assert_eq!(test::call_service(&app, heartbeat().to_request()).await.status(), StatusCode::CONFLICT);
+        let req = test::TestRequest::post().uri("/failure").set_json(json!({ "protocol_version": 3, "id": "abc", "reason": "late" }));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_web::test]
+    async fn bad_payloads_get_problem_responses() {
+        use actix_web::{http::{StatusCode, header}, test};
+
+        let store = web::Data::new(AppData::new(Arc::new(store::MemoryStore), EventLog::new(events::DEFAULT_CAPACITY)));
+        let app = test::init_service(App::new()
+            .app_data(store.clone())
+            .app_data(web::Data::new(ResultVerifier { key: None }))
+            .configure(routes(server::DEFAULT_MAX_PAYLOAD_BYTES))).await;
+        let send = |req: test::TestRequest| test::call_service(&app, req.to_request());
+
+        let malformed = test::TestRequest::put()
+            .uri("/result")
+            .insert_header(header::ContentType::json())
+            .set_payload(r#"{ "protocol_version": 3, "id": "abc", "primes": [2, 3"#);
+        let res = send(malformed).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/problem+json");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["status"], 400);
+        assert_eq!(body["type"], "payload");
+
+        let empty = test::TestRequest::put().uri("/result").set_json(json!({ "protocol_version": 3, "id": "abc", "primes": [] }));
+        let res = send(empty).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["type"], "validation");
+
+        let bad_id = test::TestRequest::post().uri("/register").set_json(json!({ "protocol_version": 3, "id": "../../etc" }));
+        assert_eq!(send(bad_id).await.status(), StatusCode::BAD_REQUEST);
+
+        let oversized = test::TestRequest::put()
+            .uri("/result")
+            .insert_header(header::ContentType::json())
+            .set_payload(vec![b' '; server::DEFAULT_MAX_PAYLOAD_BYTES + 1]);
+        assert_eq!(send(oversized).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        assert!(store.sieve_map.is_empty());
+        assert_eq!(store.metrics.rejected_results.with_label_values(&["validation"]).get(), 1);
+    }
+
+    #[actix_web::test]
+    async fn resubmitted_results_are_idempotent() {
+        use actix_web::{http::StatusCode, test};
+
+        let store = web::Data::new(AppData::new(Arc::new(store::MemoryStore), EventLog::new(events::DEFAULT_CAPACITY)));
+        let app = test::init_service(App::new()
+            .app_data(store.clone())
+            .app_data(web::Data::new(ResultVerifier { key: None }))
+            .configure(routes(server::DEFAULT_MAX_PAYLOAD_BYTES))).await;
+        let result = |primes: serde_json::Value| test::TestRequest::put()
+            .uri("/result")
+            .set_json(json!({ "protocol_version": 3, "id": "abc", "primes": primes }));
+
+        assert_eq!(test::call_service(&app, result(json!([2, 3, 5])).to_request()).await.status(), StatusCode::OK);
+        assert_eq!(test::call_service(&app, result(json!([2, 3, 5])).to_request()).await.status(), StatusCode::OK);
+
+        let res = test::call_service(&app, result(json!([2, 3, 7])).to_request()).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["type"], "result-conflict");
+
+        let worker = store.sieve_map.get("abc").unwrap();
+        assert_eq!(worker.results.as_ref().map(|r| r.max_prime), Some(5));
+        assert_eq!(worker.result_attempts, 3);
+        assert_eq!(store.metrics.results.get(), 1);
+        assert_eq!(store.metrics.duplicate_results.get(), 1);
+    }
+
+    #[actix_web::test]
+    async fn concurrent_registers_and_results_do_not_panic() {
+        const WORKERS: usize = 300;
+        let store = web::Data::new(AppData::new(Arc::new(store::MemoryStore), EventLog::new(events::DEFAULT_CAPACITY)));
+        let verifier = web::Data::new(ResultVerifier { key: None });
+
+        // a real server with several workers, so handlers for the same id run on different threads
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let app_store = store.clone();
+        let server = HttpServer::new(move || App::new()
+                .app_data(app_store.clone())
+                .app_data(verifier.clone())
+                .configure(routes(server::DEFAULT_MAX_PAYLOAD_BYTES)))
+            .workers(4)
+            .listen(listener).unwrap()
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let client = reqwest::Client::new();
+        let ids: Vec<String> = (0..WORKERS).map(|n| format!("worker-{}", n)).collect();
+        let registers = ids.iter().map(|id| {
+            client.post(format!("{}/register", base_url))
+                .json(&json!({ "protocol_version": 2, "id": id }))
+                .send()
+        });
+        // half of the results race their own register, the rest arrive for workers that are
+        // already known
+        let results = ids.iter().enumerate().map(|(n, id)| {
+            client.put(format!("{}/result", base_url))
+                .json(&json!({ "protocol_version": 2, "id": id, "primes": [2, 3, 5, n as u64 * 2 + 7] }))
+                .send()
+        });
+
+        let (registered, saved) = futures::join!(futures::future::join_all(registers), futures::future::join_all(results));
+        assert!(registered.iter().all(|r| r.as_ref().unwrap().status() == reqwest::StatusCode::CREATED));
+        assert!(saved.iter().all(|r| r.as_ref().unwrap().status().is_success()));
+
+        // two different results for a worker nobody registered - one is stored, the other conflicts
+        let conflicting = (0..50).flat_map(|n| [7, 11].map(|p| {
+            client.put(format!("{}/result", base_url))
+                .json(&json!({ "protocol_version": 2, "id": format!("unregistered-{}", n), "primes": [2, 3, 5, p] }))
+                .send()
+        }));
+        let answers = futures::future::join_all(conflicting).await;
+        for pair in answers.chunks(2) {
+            let mut statuses: Vec<u16> = pair.iter().map(|r| r.as_ref().unwrap().status().as_u16()).collect();
+            statuses.sort();
+            assert_eq!(statuses, [200, 409]);
+        }
+        handle.stop(true).await;
+
+        assert_eq!(store.sieve_map.len(), WORKERS + 50);
+        for (n, id) in ids.iter().enumerate() {
+            let worker = store.sieve_map.get(id).unwrap();
+            assert_eq!(worker.results.as_ref().map(|r| r.max_prime), Some(n as u64 * 2 + 7));
+        }
+    }
+}
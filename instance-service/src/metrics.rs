@@ -0,0 +1,160 @@
+//! Prometheus metrics, served at `/metrics`. Each `AppData` owns its own registry so tests don't
+//! share counters.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix_web::{HttpResponse, web};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+
+use crate::AppData;
+use crate::lifecycle::WorkerStatus;
+
+pub struct Metrics {
+    registry: Registry,
+    pub registrations: IntCounter,
+    pub results: IntCounter,
+    pub result_attempts: IntCounter,
+    pub duplicate_results: IntCounter,
+    pub unregistered_results: IntCounter,
+    pub rejected_results: IntCounterVec,
+    pub injected_faults: IntCounterVec,
+    request_duration: HistogramVec,
+    request_bytes: HistogramVec,
+    store_duration: HistogramVec,
+    store_errors: IntCounterVec,
+    workers: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            registrations: IntCounter::new("instance_registrations_total", "Sieve registrations accepted").unwrap(),
+            results: IntCounter::new("instance_results_total", "Sieve results accepted").unwrap(),
+            result_attempts: IntCounter::new("instance_result_attempts_total", "Result submissions received, including retries and rejections").unwrap(),
+            duplicate_results: IntCounter::new("instance_duplicate_results_total", "Resubmitted results that matched the one already accepted").unwrap(),
+            unregistered_results: IntCounter::new("instance_unregistered_results_total", "Results from workers that never registered").unwrap(),
+            rejected_results: IntCounterVec::new(
+                Opts::new("instance_rejected_results_total", "Results rejected, by reason"),
+                &["reason"]).unwrap(),
+            injected_faults: IntCounterVec::new(
+                Opts::new("instance_injected_faults_total", "Faults injected by fault rules, by route and fault"),
+                &["route", "fault"]).unwrap(),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new("instance_http_request_duration_seconds", "HTTP request latency by route"),
+                &["method", "route", "status"]).unwrap(),
+            // 64 bytes up to 16MiB, the echo endpoint's limit
+            request_bytes: HistogramVec::new(
+                HistogramOpts::new("instance_http_request_body_bytes", "HTTP request body size by route")
+                    .buckets(prometheus::exponential_buckets(64.0, 4.0, 10).unwrap()),
+                &["route"]).unwrap(),
+            store_duration: HistogramVec::new(
+                HistogramOpts::new("instance_store_operation_duration_seconds", "Worker store operation latency")
+                    .buckets(prometheus::exponential_buckets(0.0005, 2.0, 14).unwrap()),
+                &["backend", "op"]).unwrap(),
+            store_errors: IntCounterVec::new(
+                Opts::new("instance_store_errors_total", "Failed worker store operations"),
+                &["backend", "op"]).unwrap(),
+            workers: IntGaugeVec::new(
+                Opts::new("instance_workers", "Current workers by lifecycle state"),
+                &["status"]).unwrap(),
+            registry,
+        };
+
+        let registry = &metrics.registry;
+        registry.register(Box::new(metrics.registrations.clone())).unwrap();
+        registry.register(Box::new(metrics.results.clone())).unwrap();
+        registry.register(Box::new(metrics.result_attempts.clone())).unwrap();
+        registry.register(Box::new(metrics.duplicate_results.clone())).unwrap();
+        registry.register(Box::new(metrics.unregistered_results.clone())).unwrap();
+        registry.register(Box::new(metrics.rejected_results.clone())).unwrap();
+        registry.register(Box::new(metrics.injected_faults.clone())).unwrap();
+        registry.register(Box::new(metrics.request_duration.clone())).unwrap();
+        registry.register(Box::new(metrics.request_bytes.clone())).unwrap();
+        registry.register(Box::new(metrics.store_duration.clone())).unwrap();
+        registry.register(Box::new(metrics.store_errors.clone())).unwrap();
+        registry.register(Box::new(metrics.workers.clone())).unwrap();
+        metrics
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration, body_bytes: Option<u64>) {
+        self.request_duration
+            .with_label_values(&[method, route, &status.to_string()])
+            .observe(elapsed.as_secs_f64());
+        if let Some(bytes) = body_bytes {
+            self.request_bytes.with_label_values(&[route]).observe(bytes as f64);
+        }
+    }
+
+    pub fn observe_store(&self, backend: &str, op: &str, elapsed: Duration, ok: bool) {
+        self.store_duration.with_label_values(&[backend, op]).observe(elapsed.as_secs_f64());
+        if !ok {
+            self.store_errors.with_label_values(&[backend, op]).inc();
+        }
+    }
+
+    pub fn reject_result(&self, reason: &str) {
+        self.rejected_results.with_label_values(&[reason]).inc();
+    }
+}
+
+fn render(store: &AppData) -> Result<String, prometheus::Error> {
+    // worker counts are taken from the map at scrape time so they can never drift. they're tallied
+    // locally and set in one go so a concurrent scrape never sees a half-filled gauge
+    let mut counts: HashMap<WorkerStatus, i64> = HashMap::new();
+    for w in store.sieve_map.iter() {
+        *counts.entry(w.status).or_default() += 1;
+    }
+    for status in WorkerStatus::ALL {
+        let n = counts.get(&status).copied().unwrap_or(0);
+        store.metrics.workers.with_label_values(&[status.as_str()]).set(n);
+    }
+
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&store.metrics.registry.gather(), &mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[tracing::instrument(skip(store))]
+pub async fn serve_metrics(store: web::Data<AppData>) -> HttpResponse {
+    match render(&store) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(TextEncoder::new().format_type())
+            .body(body),
+        Err(e) => {
+            tracing::error!("Failed to encode metrics: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn render_includes_counters_and_worker_gauge() {
+        let store = AppData::new(Arc::new(crate::store::MemoryStore), crate::EventLog::new(10));
+        store.metrics.registrations.inc();
+        store.metrics.reject_result("integrity");
+        store.metrics.observe_request("PUT", "/result", 200, Duration::from_millis(3), Some(2048));
+        store.metrics.observe_store("redis", "save", Duration::from_millis(1), false);
+
+        let text = render(&store).unwrap();
+        assert!(text.contains("instance_registrations_total 1"));
+        assert!(text.contains("instance_rejected_results_total{reason=\"integrity\"} 1"));
+        assert!(text.contains("instance_http_request_duration_seconds_count{method=\"PUT\",route=\"/result\",status=\"200\"} 1"));
+        assert!(text.contains("instance_store_errors_total{backend=\"redis\",op=\"save\"} 1"));
+    }
+
+    #[test]
+    fn worker_gauge_reports_every_status_including_zero() {
+        let store = AppData::new(Arc::new(crate::store::MemoryStore), crate::EventLog::new(10));
+        let text = render(&store).unwrap();
+        for status in WorkerStatus::ALL {
+            assert!(text.contains(&format!("instance_workers{{status=\"{}\"}} 0", status.as_str())), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Read side of the worker store - listing with filters and cursor pagination, and single lookups.
+
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use crate::error::ApiError;
+use crate::{AppData, Worker, WorkerStatus};
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListParams {
+    status: Option<WorkerStatus>,
+    has_results: Option<bool>,
+    registered_after: Option<DateTime<Utc>>,
+    // ID of the last worker on the previous page
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerPage {
+    workers: Vec<Worker>,
+    // pass back as `cursor` to get the next page, None on the last page
+    next_cursor: Option<String>,
+}
+
+impl ListParams {
+    fn matches(&self, worker: &Worker) -> bool {
+        self.status.is_none_or(|s| worker.status == s)
+            && self.has_results.is_none_or(|h| worker.results.is_some() == h)
+            && self.registered_after.is_none_or(|after| worker.registered_at.is_some_and(|at| at > after))
+            && self.cursor.as_ref().is_none_or(|c| worker.id > *c)
+    }
+}
+
+/// Pages through workers ordered by ID. Ordering by the key keeps cursors stable while workers
+/// are still being added, since new IDs never shift a page that was already handed out.
+fn page(workers: &DashMap<String, Worker>, params: &ListParams) -> WorkerPage {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut matched: Vec<Worker> = workers.iter()
+        .filter(|w| params.matches(w.value()))
+        .map(|w| w.value().clone())
+        .collect();
+    matched.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+
+    let next_cursor = if matched.len() > limit {
+        matched.truncate(limit);
+        matched.last().map(|w| w.id.clone())
+    } else {
+        None
+    };
+    WorkerPage { workers: matched, next_cursor }
+}
+
+#[tracing::instrument(skip(store))]
+pub async fn list_workers(store: web::Data<AppData>, params: web::Query<ListParams>) -> HttpResponse {
+    let page = page(&store.sieve_map, &params);
+    tracing::debug!("Returning {} workers, next cursor {:?}", page.workers.len(), page.next_cursor);
+    HttpResponse::Ok().json(page)
+}
+
+#[tracing::instrument(skip(store))]
+pub async fn get_worker(store: web::Data<AppData>, id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    match store.sieve_map.get(id.as_str()) {
+        Some(worker) => Ok(HttpResponse::Ok().json(worker.value())),
+        None => Err(ApiError::NotFound(format!("no worker with ID '{}'", id))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PrimeResult;
+    use chrono::Duration;
+
+    fn store() -> (DashMap<String, Worker>, DateTime<Utc>) {
+        let base = Utc::now();
+        let workers = DashMap::new();
+        for n in 0..25u64 {
+            let done = n % 2 == 0;
+            let id = format!("w-{:02}", n);
+            workers.insert(id.clone(), Worker {
+                protocol_version: 2,
+                status: if done { WorkerStatus::Completed } else { WorkerStatus::Registered },
+                registered_at: Some(base + Duration::seconds(n as i64)),
+                results: done.then_some(PrimeResult { quantity: 1, max_prime: n }),
+                ..Worker::test(&id)
+            });
+        }
+        (workers, base)
+    }
+
+    #[test]
+    fn cursor_walks_every_worker_once() {
+        let (workers, _) = store();
+        let mut params = ListParams { limit: Some(10), ..Default::default() };
+        let mut seen = Vec::new();
+        loop {
+            let p = page(&workers, &params);
+            seen.extend(p.workers.into_iter().map(|w| w.id));
+            match p.next_cursor {
+                Some(c) => params.cursor = Some(c),
+                None => break,
+            }
+        }
+        let expected: Vec<String> = (0..25).map(|n| format!("w-{:02}", n)).collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn filters_combine() {
+        let (workers, base) = store();
+        let params = ListParams {
+            status: Some(WorkerStatus::Completed),
+            registered_after: Some(base + Duration::seconds(19)),
+            ..Default::default()
+        };
+        let ids: Vec<String> = page(&workers, &params).workers.into_iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec!["w-20", "w-22", "w-24"]);
+
+        let params = ListParams { has_results: Some(false), ..Default::default() };
+        assert_eq!(page(&workers, &params).workers.len(), 12);
+    }
+
+    #[test]
+    fn query_string_uses_kebab_case() {
+        let params = web::Query::<ListParams>::from_query("status=registered&has-results=false&limit=5").unwrap();
+        assert_eq!(params.status, Some(WorkerStatus::Registered));
+        assert_eq!(params.has_results, Some(false));
+        assert_eq!(params.limit, Some(5));
+    }
+}
@@ -0,0 +1,67 @@
+//! Admin view of the runs kept in the backing store, so a long-lived shared Redis or SQLite file
+//! can be cleared of runs nobody needs any more. Both routes need the admin token (see
+//! [`crate::admin`]), since a purge can't be undone.
+
+use std::time::Instant;
+
+use actix_web::{HttpResponse, web};
+use serde_json::json;
+
+use crate::AppData;
+use crate::admin::Admin;
+use crate::error::{self, ApiError};
+
+#[tracing::instrument(skip(store))]
+pub async fn list_runs(_admin: Admin, store: web::Data<AppData>) -> Result<HttpResponse, ApiError> {
+    let start = Instant::now();
+    let runs = store.store.list_runs().await;
+    store.metrics.observe_store(store.store.name(), "list_runs", start.elapsed(), runs.is_ok());
+    Ok(HttpResponse::Ok().json(runs?))
+}
+
+#[tracing::instrument(skip(store))]
+pub async fn purge_run(_admin: Admin, store: web::Data<AppData>, run_id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    error::validate_id(&run_id)?;
+    // the live run's records back the in-process map, so deleting them would only half-forget it
+    if store.store.run_id() == Some(run_id.as_str()) {
+        return Err(ApiError::Validation(format!("run '{}' is the one this instance is serving and can't be purged", run_id)));
+    }
+
+    let start = Instant::now();
+    let purged = store.store.purge_run(&run_id).await;
+    store.metrics.observe_store(store.store.name(), "purge_run", start.elapsed(), purged.is_ok());
+    match purged? {
+        0 => Err(ApiError::NotFound(format!("no stored run '{}'", run_id))),
+        workers => {
+            tracing::warn!("Purged run '{}' with {} workers from the '{}' store", run_id, workers, store.store.name());
+            Ok(HttpResponse::Ok().json(json!({ "run_id": run_id.as_str(), "workers_purged": workers })))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use actix_web::{App, http::StatusCode, test};
+    use crate::admin::AdminToken;
+
+    #[actix_web::test]
+    async fn purging_needs_the_admin_token() {
+        let store = web::Data::new(AppData::new(Arc::new(crate::store::MemoryStore), crate::EventLog::new(10)));
+        let app = test::init_service(App::new()
+            .app_data(store)
+            .app_data(web::Data::new(AdminToken::new(Some(String::from("s3cret")))))
+            .route("/admin/runs", web::get().to(list_runs))
+            .route("/admin/runs/{run_id}", web::delete().to(purge_run))).await;
+
+        let res = test::call_service(&app, test::TestRequest::delete().uri("/admin/runs/run-b").to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = test::call_service(&app, test::TestRequest::get().uri("/admin/runs").to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // the memory store keeps no runs, so an authorized purge gets as far as not finding one
+        let req = test::TestRequest::delete().uri("/admin/runs/run-b").insert_header(("Authorization", "Bearer s3cret")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,173 @@
+//! HTTP server settings - where to listen, how many workers, and whether to terminate TLS (with
+//! optional client certificate verification) in-process rather than leaving it to the mesh.
+
+use std::{fs::File, io::BufReader, str::FromStr, time::Duration};
+
+use actix_web::http::KeepAlive;
+use rustls::{Certificate, PrivateKey, RootCertStore, server::AllowAnyAuthenticatedClient};
+
+/// Default cap on JSON request bodies - a few million primes in the largest sieve results.
+pub const DEFAULT_MAX_PAYLOAD_BYTES: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// None leaves actix's default of one worker per physical core.
+    pub workers: Option<usize>,
+    /// None leaves actix's default, zero disables keep-alive.
+    pub keep_alive: Option<Duration>,
+    pub max_payload_bytes: usize,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// When set, clients must present a certificate signed by one of these CAs.
+    pub client_ca_path: Option<String>,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> anyhow::Result<ServerConfig> {
+        let tls = match (std::env::var("TLS_CERT_PATH").ok(), std::env::var("TLS_KEY_PATH").ok()) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path,
+                key_path,
+                client_ca_path: std::env::var("TLS_CLIENT_CA_PATH").ok(),
+            }),
+            (None, None) => {
+                if std::env::var("TLS_CLIENT_CA_PATH").is_ok() {
+                    return Err(anyhow::anyhow!("TLS_CLIENT_CA_PATH needs TLS_CERT_PATH and TLS_KEY_PATH to be set as well"));
+                }
+                None
+            },
+            _ => return Err(anyhow::anyhow!("TLS_CERT_PATH and TLS_KEY_PATH must be set together")),
+        };
+
+        let config = ServerConfig {
+            bind_address: std::env::var("BIND_ADDRESS").unwrap_or_else(|_| String::from("0.0.0.0")),
+            port: env_parse("PORT")?.unwrap_or(8080),
+            workers: env_parse("HTTP_WORKERS")?,
+            keep_alive: env_parse("KEEP_ALIVE_SECS")?.map(Duration::from_secs),
+            max_payload_bytes: env_parse("MAX_PAYLOAD_BYTES")?.unwrap_or(DEFAULT_MAX_PAYLOAD_BYTES),
+            tls,
+        };
+        if config.workers == Some(0) {
+            return Err(anyhow::anyhow!("HTTP_WORKERS must be at least 1"));
+        }
+        Ok(config)
+    }
+
+    pub fn address(&self) -> (&str, u16) {
+        (&self.bind_address, self.port)
+    }
+
+    pub fn keep_alive(&self) -> Option<KeepAlive> {
+        self.keep_alive.map(|d| if d.is_zero() { KeepAlive::Disabled } else { KeepAlive::Timeout(d) })
+    }
+}
+
+impl TlsConfig {
+    /// Loads the certificate chain, key and client CAs into a rustls config, so bad paths or PEM
+    /// files fail at startup rather than on the first handshake.
+    pub fn load(&self) -> anyhow::Result<rustls::ServerConfig> {
+        let certs = read_certs(&self.cert_path, "TLS_CERT_PATH")?;
+        let key = read_key(&self.key_path)?;
+
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.client_ca_path {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for ca in read_certs(path, "TLS_CLIENT_CA_PATH")? {
+                    roots.add(&ca).map_err(|e| anyhow::anyhow!("Invalid CA certificate in TLS_CLIENT_CA_PATH '{}': {}", path, e))?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            },
+            None => builder.with_no_client_auth(),
+        };
+        builder.with_single_cert(certs, key)
+            .map_err(|e| anyhow::anyhow!("Unable to use certificate '{}' with key '{}': {}", self.cert_path, self.key_path, e))
+    }
+}
+
+fn read_certs(path: &str, var: &str) -> anyhow::Result<Vec<Certificate>> {
+    let file = File::open(path).map_err(|e| anyhow::anyhow!("Unable to open {} '{}': {}", var, path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| anyhow::anyhow!("Unable to read {} '{}': {}", var, path, e))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No PEM certificates found in {} '{}'", var, path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &str) -> anyhow::Result<PrivateKey> {
+    let file = File::open(path).map_err(|e| anyhow::anyhow!("Unable to open TLS_KEY_PATH '{}': {}", path, e))?;
+    let mut reader = BufReader::new(file);
+    // first key of any supported encoding wins
+    loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key))) => return Ok(PrivateKey(key)),
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(anyhow::anyhow!("No PEM private key found in TLS_KEY_PATH '{}'", path)),
+            Err(e) => return Err(anyhow::anyhow!("Unable to read TLS_KEY_PATH '{}': {}", path, e)),
+        }
+    }
+}
+
+fn env_parse<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(val) => val.parse::<T>()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Invalid value '{}' for {}: {}", val, name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_keep_alive_disables_it() {
+        let mut config = ServerConfig {
+            bind_address: String::from("127.0.0.1"),
+            port: 8443,
+            workers: None,
+            keep_alive: None,
+            max_payload_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+            tls: None,
+        };
+        assert_eq!(config.keep_alive(), None);
+        config.keep_alive = Some(Duration::ZERO);
+        assert_eq!(config.keep_alive(), Some(KeepAlive::Disabled));
+        config.keep_alive = Some(Duration::from_secs(30));
+        assert_eq!(config.keep_alive(), Some(KeepAlive::Timeout(Duration::from_secs(30))));
+    }
+
+    #[test]
+    fn unreadable_tls_files_name_the_variable() {
+        let dir = std::env::temp_dir().join(format!("instance-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+
+        let tls = TlsConfig {
+            cert_path: String::from("/nonexistent/cert.pem"),
+            key_path: empty.to_string_lossy().into_owned(),
+            client_ca_path: None,
+        };
+        let err = tls.load().unwrap_err().to_string();
+        assert!(err.contains("TLS_CERT_PATH"), "{}", err);
+
+        let tls = TlsConfig { cert_path: empty.to_string_lossy().into_owned(), ..tls };
+        let err = tls.load().unwrap_err().to_string();
+        assert!(err.contains("No PEM certificates"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
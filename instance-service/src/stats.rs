@@ -0,0 +1,174 @@
+//! Run summary built from the worker store - the numbers we want at the end of every load test.
+
+use std::collections::BTreeMap;
+
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::{AppData, Worker, WorkerStatus};
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Distribution {
+    pub count: usize,
+    pub min: u64,
+    pub max: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+impl Distribution {
+    fn from_values(mut values: Vec<u64>) -> Option<Distribution> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable();
+        // u128 so summing max primes near u64::MAX can't overflow
+        let sum: u128 = values.iter().map(|v| *v as u128).sum();
+        Some(Distribution {
+            count: values.len(),
+            min: values[0],
+            max: values[values.len() - 1],
+            mean: (sum / values.len() as u128) as u64,
+            p50: percentile(&values, 50),
+            p90: percentile(&values, 90),
+            p99: percentile(&values, 99),
+        })
+    }
+}
+
+// nearest rank over sorted values
+fn percentile(sorted: &[u64], pct: usize) -> u64 {
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunStats {
+    pub registered: usize,
+    // finished with a full result - partial and failed workers are only counted in `statuses`
+    pub completed: usize,
+    // registered or running, still waiting on an outcome
+    pub outstanding: usize,
+    // worker count per lifecycle state - timed-out is how many sieves silently disappeared
+    pub statuses: BTreeMap<WorkerStatus, usize>,
+    pub total_primes: u64,
+    pub max_prime: Option<Distribution>,
+    pub quantity: Option<Distribution>,
+    // register to result per worker, only for workers that did both
+    pub worker_duration_ms: Option<Distribution>,
+    pub first_register: Option<DateTime<Utc>>,
+    pub last_result: Option<DateTime<Utc>>,
+    // first register to last result
+    pub run_duration_ms: Option<u64>,
+}
+
+fn summarize(workers: &DashMap<String, Worker>) -> RunStats {
+    let mut stats = RunStats {
+        registered: 0,
+        completed: 0,
+        outstanding: 0,
+        statuses: BTreeMap::new(),
+        total_primes: 0,
+        max_prime: None,
+        quantity: None,
+        worker_duration_ms: None,
+        first_register: None,
+        last_result: None,
+        run_duration_ms: None,
+    };
+    let (mut max_primes, mut quantities, mut durations) = (Vec::new(), Vec::new(), Vec::new());
+
+    for entry in workers.iter() {
+        let w = entry.value();
+        *stats.statuses.entry(w.status).or_default() += 1;
+        match w.status {
+            WorkerStatus::Completed => stats.completed += 1,
+            WorkerStatus::Registered | WorkerStatus::Running => stats.outstanding += 1,
+            _ => {},
+        }
+        if let Some(at) = w.registered_at {
+            stats.registered += 1;
+            stats.first_register = Some(stats.first_register.map_or(at, |f| f.min(at)));
+        }
+        // partial results still found real primes, so they count towards the prime totals
+        if let Some(res) = &w.results {
+            stats.total_primes = stats.total_primes.saturating_add(res.quantity);
+            max_primes.push(res.max_prime);
+            quantities.push(res.quantity);
+        }
+        if let Some(done) = w.completed_at {
+            stats.last_result = Some(stats.last_result.map_or(done, |l| l.max(done)));
+            if let Some(at) = w.registered_at {
+                durations.push((done - at).num_milliseconds().max(0) as u64);
+            }
+        }
+    }
+
+    stats.max_prime = Distribution::from_values(max_primes);
+    stats.quantity = Distribution::from_values(quantities);
+    stats.worker_duration_ms = Distribution::from_values(durations);
+    if let (Some(first), Some(last)) = (stats.first_register, stats.last_result) {
+        stats.run_duration_ms = Some((last - first).num_milliseconds().max(0) as u64);
+    }
+    stats
+}
+
+#[tracing::instrument(skip(store))]
+pub async fn run_stats(store: web::Data<AppData>) -> HttpResponse {
+    HttpResponse::Ok().json(summarize(&store.sieve_map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PrimeResult;
+    use chrono::Duration;
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let d = Distribution::from_values((1..=100).rev().collect()).unwrap();
+        assert_eq!((d.min, d.max, d.mean), (1, 100, 50));
+        assert_eq!((d.p50, d.p90, d.p99), (50, 90, 99));
+        assert_eq!(Distribution::from_values(vec![]), None);
+        assert_eq!(Distribution::from_values(vec![7]).unwrap().p99, 7);
+    }
+
+    #[test]
+    fn summary_counts_and_timings() {
+        let base = Utc::now();
+        let workers = DashMap::new();
+        for n in 0..6u64 {
+            let status = match n {
+                0..=2 => WorkerStatus::Completed,
+                3 => WorkerStatus::Registered,
+                4 => WorkerStatus::Failed,
+                _ => WorkerStatus::Partial,
+            };
+            let done = matches!(status, WorkerStatus::Completed | WorkerStatus::Partial);
+            workers.insert(n.to_string(), Worker {
+                protocol_version: 2,
+                status,
+                registered_at: Some(base + Duration::milliseconds(n as i64)),
+                completed_at: done.then(|| base + Duration::milliseconds(100 * (n as i64 + 1))),
+                failure: (status == WorkerStatus::Failed).then(|| "out of memory".to_string()),
+                results: done.then_some(PrimeResult { quantity: 10 * (n + 1), max_prime: u64::MAX - n }),
+                ..Worker::test(&n.to_string())
+            });
+        }
+
+        let stats = summarize(&workers);
+        assert_eq!((stats.registered, stats.completed, stats.outstanding), (6, 3, 1));
+        assert_eq!(stats.statuses[&WorkerStatus::Completed], 3);
+        assert_eq!(stats.statuses[&WorkerStatus::Failed], 1);
+        assert_eq!(stats.statuses[&WorkerStatus::Partial], 1);
+        assert_eq!(stats.total_primes, 120);
+        assert_eq!(stats.quantity.unwrap().count, 4);
+        assert_eq!(stats.max_prime.unwrap().max, u64::MAX);
+        assert_eq!(stats.worker_duration_ms.unwrap().max, 595);
+        assert_eq!(stats.run_duration_ms, Some(600));
+    }
+}
@@ -0,0 +1,541 @@
+//! Durable backing stores for worker records.
+//!
+//! Instance service answers every query from its in-process map and writes each change through to
+//! the configured [`WorkerStore`]. On startup the map is rebuilt from the store, so with a durable
+//! backend a restart doesn't lose the run.
+
+use std::{collections::HashMap, path::Path, sync::{Arc, Mutex}, time::Duration};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use sieve_protocol::redis_keys::{index_key, index_pattern, run_from_index_key, worker_key};
+
+use crate::{PrimeResult, Worker, WorkerStatus};
+use crate::redis_config::{RedisConfig, RedisConnection};
+
+#[async_trait]
+pub trait WorkerStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Writes the whole record, replacing whatever was stored for that worker.
+    async fn save(&self, worker: &Worker) -> anyhow::Result<()>;
+
+    /// Reads back every worker recorded for this run.
+    async fn load_all(&self) -> anyhow::Result<Vec<Worker>>;
+
+    /// Cheapest round trip to the backend, for readiness checks.
+    async fn ping(&self) -> anyhow::Result<()>;
+
+    /// The run this instance reads and writes, for stores that keep more than one.
+    fn run_id(&self) -> Option<&str> {
+        None
+    }
+
+    /// Every run with records in the backend, this one included.
+    async fn list_runs(&self) -> anyhow::Result<Vec<RunSummary>> {
+        Ok(Vec::new())
+    }
+
+    /// Deletes everything recorded for `run_id` and returns how many workers went with it.
+    async fn purge_run(&self, _run_id: &str) -> anyhow::Result<u64> {
+        Ok(0)
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub workers: u64,
+    // None when the run's keys don't expire
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
+    pub current: bool,
+}
+
+/// Builds the store named by `WORKER_STORE` (memory, redis or sqlite). When unset, redis is used
+/// if `REDIS_URL` or `REDIS_SENTINELS` is present and memory otherwise, so existing deployments
+/// keep their behavior.
+pub async fn store_from_env() -> anyhow::Result<Arc<dyn WorkerStore>> {
+    let configured = match std::env::var("WORKER_STORE") {
+        Ok(val) => val.trim().to_ascii_lowercase(),
+        Err(_) if std::env::var("REDIS_URL").is_ok() || std::env::var("REDIS_SENTINELS").is_ok() => String::from("redis"),
+        Err(_) => String::from("memory"),
+    };
+    // workers are stored per run so several runs can share one backend without mixing results
+    let run_id = std::env::var("RUN_ID").unwrap_or_else(|_| String::from("default"));
+    crate::error::validate_id(&run_id).map_err(|e| anyhow::anyhow!("Invalid value '{}' for RUN_ID: {}", run_id, e))?;
+    let ttl = match std::env::var("RUN_TTL_SECS") {
+        Ok(val) => match val.parse::<u64>() {
+            Ok(0) => None,
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(e) => return Err(anyhow::anyhow!("Invalid value '{}' for RUN_TTL_SECS: {}", val, e)),
+        },
+        Err(_) => None,
+    };
+
+    let store: Arc<dyn WorkerStore> = match configured.as_str() {
+        "memory" => Arc::new(MemoryStore),
+        "redis" => {
+            let con = RedisConfig::from_env()?.connect().await?;
+            Arc::new(RedisStore::new(con, run_id.clone(), ttl))
+        },
+        "sqlite" => {
+            if ttl.is_some() {
+                tracing::warn!("RUN_TTL_SECS only applies to the redis store - old sqlite runs are removed through /admin/runs");
+            }
+            let path = std::env::var("SQLITE_PATH").unwrap_or_else(|_| String::from("instance-service.db"));
+            Arc::new(SqliteStore::open(Path::new(&path), run_id.clone())
+                .map_err(|e| anyhow::anyhow!("Unable to open SQLite database at {}: {}", path, e))?)
+        },
+        other => return Err(anyhow::anyhow!("Unknown worker store '{}' - expected memory, redis or sqlite", other)),
+    };
+    tracing::info!("Using '{}' worker store for run '{}'", store.name(), run_id);
+    Ok(store)
+}
+
+/// Keeps nothing beyond the in-process map - workers are gone once the process exits. Meant for
+/// local development and tests.
+pub struct MemoryStore;
+
+#[async_trait]
+impl WorkerStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn save(&self, _worker: &Worker) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn load_all(&self) -> anyhow::Result<Vec<Worker>> {
+        Ok(Vec::new())
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Each worker is a hash at `prime-gen:{run}:worker:{id}` and the run keeps a set of its worker
+/// IDs at `prime-gen:{run}:workers`, so loading never needs a SCAN over the whole keyspace. With a
+/// TTL every key expires that long after it was last written, so an abandoned run cleans itself up.
+pub struct RedisStore {
+    con: RedisConnection,
+    run_id: String,
+    ttl: Option<Duration>,
+}
+
+impl RedisStore {
+    pub fn new(con: RedisConnection, run_id: String, ttl: Option<Duration>) -> RedisStore {
+        RedisStore { con, run_id, ttl }
+    }
+
+    fn worker_key(&self, id: &str) -> String {
+        worker_key(&self.run_id, id)
+    }
+
+    fn index_key(&self) -> String {
+        index_key(&self.run_id)
+    }
+}
+
+#[async_trait]
+impl WorkerStore for RedisStore {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    // the hash and index are updated in one MULTI so a crash can't leave an indexed worker
+    // without its fields
+    async fn save(&self, worker: &Worker) -> anyhow::Result<()> {
+        let mut con = self.con.manager();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_multiple(self.worker_key(&worker.id), &to_fields(worker)).ignore()
+            .sadd(self.index_key(), &worker.id).ignore();
+        if let Some(ttl) = self.ttl {
+            let secs = ttl.as_secs() as usize;
+            pipe.expire(self.worker_key(&worker.id), secs).ignore()
+                .expire(self.index_key(), secs).ignore();
+        }
+        self.con.checked(pipe.query_async::<_, ()>(&mut con).await).await?;
+        Ok(())
+    }
+
+    // records that no longer parse are logged and skipped rather than stopping the service from
+    // starting
+    async fn load_all(&self) -> anyhow::Result<Vec<Worker>> {
+        let mut con = self.con.manager();
+        let ids: Vec<String> = self.con.checked(redis::cmd("SMEMBERS").arg(self.index_key()).query_async(&mut con).await).await?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.hgetall(self.worker_key(id));
+        }
+        let records: Vec<HashMap<String, String>> = self.con.checked(pipe.query_async(&mut con).await).await?;
+
+        // a worker hash that expired ahead of the index leaves its ID behind
+        let expired: Vec<&String> = ids.iter().zip(&records).filter(|(_, fields)| fields.is_empty()).map(|(id, _)| id).collect();
+        if !expired.is_empty() {
+            tracing::info!("Dropping {} expired workers from the index for run '{}'", expired.len(), self.run_id);
+            self.con.checked(redis::cmd("SREM").arg(self.index_key()).arg(&expired).query_async::<_, ()>(&mut con).await).await?;
+        }
+
+        Ok(ids.iter().zip(records).filter(|(_, fields)| !fields.is_empty()).filter_map(|(id, fields)| match from_fields(&fields) {
+            Ok(worker) => Some(worker),
+            Err(e) => {
+                tracing::warn!("Skipping stored worker {} that could not be read back: {}", id, e);
+                None
+            }
+        }).collect())
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        self.con.ping().await
+    }
+
+    fn run_id(&self) -> Option<&str> {
+        Some(&self.run_id)
+    }
+
+    // runs are found by their index keys, so ones written before anything kept a list of runs
+    // still show up - it's a SCAN, but only on an admin request
+    async fn list_runs(&self) -> anyhow::Result<Vec<RunSummary>> {
+        let mut con = self.con.manager();
+        let pattern = index_pattern();
+        let mut keys = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, batch): (u64, Vec<String>) = self.con.checked(redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(1000)
+                .query_async(&mut con).await).await?;
+            keys.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        keys.sort();
+        keys.dedup();
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.scard(key).ttl(key);
+        }
+        let counts: Vec<(u64, i64)> = self.con.checked(pipe.query_async(&mut con).await).await?;
+        Ok(keys.iter().zip(counts).filter_map(|(key, (workers, ttl))| {
+            let run_id = run_from_index_key(key)?;
+            Some(RunSummary {
+                run_id: run_id.to_string(),
+                workers,
+                expires_in_secs: u64::try_from(ttl).ok(),
+                current: run_id == self.run_id,
+            })
+        }).collect())
+    }
+
+    async fn purge_run(&self, run_id: &str) -> anyhow::Result<u64> {
+        let mut con = self.con.manager();
+        let ids: Vec<String> = self.con.checked(redis::cmd("SMEMBERS").arg(index_key(run_id)).query_async(&mut con).await).await?;
+        for chunk in ids.chunks(500) {
+            let keys: Vec<String> = chunk.iter().map(|id| worker_key(run_id, id)).collect();
+            self.con.checked(redis::cmd("DEL").arg(keys).query_async::<_, ()>(&mut con).await).await?;
+        }
+        self.con.checked(redis::cmd("DEL").arg(index_key(run_id)).query_async::<_, ()>(&mut con).await).await?;
+        Ok(ids.len() as u64)
+    }
+}
+
+/// Embedded database for long runs that should keep a queryable history. The full record is kept
+/// as JSON next to the columns worth filtering on.
+pub struct SqliteStore {
+    // rusqlite is blocking, so every call runs on the blocking pool behind this lock
+    con: Arc<Mutex<rusqlite::Connection>>,
+    run_id: String,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path, run_id: String) -> rusqlite::Result<SqliteStore> {
+        let con = rusqlite::Connection::open(path)?;
+        con.execute_batch(
+            "CREATE TABLE IF NOT EXISTS workers (
+                run_id TEXT NOT NULL,
+                id TEXT NOT NULL,
+                status TEXT NOT NULL,
+                registered_at TEXT,
+                completed_at TEXT,
+                quantity INTEGER,
+                -- text because primes can go past i64
+                max_prime TEXT,
+                record TEXT NOT NULL,
+                PRIMARY KEY (run_id, id)
+            );",
+        )?;
+        Ok(SqliteStore { con: Arc::new(Mutex::new(con)), run_id })
+    }
+}
+
+#[async_trait]
+impl WorkerStore for SqliteStore {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    async fn save(&self, worker: &Worker) -> anyhow::Result<()> {
+        let con = self.con.clone();
+        let run_id = self.run_id.clone();
+        let worker = worker.clone();
+        let record = serde_json::to_string(&worker)?;
+        actix_web::rt::task::spawn_blocking(move || {
+            let con = con.lock().unwrap();
+            con.execute(
+                "INSERT INTO workers (run_id, id, status, registered_at, completed_at, quantity, max_prime, record)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (run_id, id) DO UPDATE SET
+                    status = excluded.status,
+                    registered_at = excluded.registered_at,
+                    completed_at = excluded.completed_at,
+                    quantity = excluded.quantity,
+                    max_prime = excluded.max_prime,
+                    record = excluded.record",
+                rusqlite::params![
+                    run_id,
+                    worker.id,
+                    worker.status.as_str(),
+                    worker.registered_at.map(|t| t.to_rfc3339()),
+                    worker.completed_at.map(|t| t.to_rfc3339()),
+                    worker.results.as_ref().map(|r| r.quantity as i64),
+                    worker.results.as_ref().map(|r| r.max_prime.to_string()),
+                    record,
+                ],
+            )
+        }).await??;
+        Ok(())
+    }
+
+    async fn load_all(&self) -> anyhow::Result<Vec<Worker>> {
+        let con = self.con.clone();
+        let run_id = self.run_id.clone();
+        let records = actix_web::rt::task::spawn_blocking(move || -> rusqlite::Result<Vec<(String, String)>> {
+            let con = con.lock().unwrap();
+            let mut stmt = con.prepare("SELECT id, record FROM workers WHERE run_id = ?1")?;
+            let rows = stmt.query_map([run_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect()
+        }).await??;
+
+        Ok(records.into_iter().filter_map(|(id, record)| match serde_json::from_str(&record) {
+            Ok(worker) => Some(worker),
+            Err(e) => {
+                tracing::warn!("Skipping stored worker {} that could not be read back: {}", id, e);
+                None
+            }
+        }).collect())
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        let con = self.con.clone();
+        actix_web::rt::task::spawn_blocking(move || {
+            con.lock().unwrap().query_row("SELECT 1", [], |row| row.get::<_, i64>(0))
+        }).await??;
+        Ok(())
+    }
+
+    fn run_id(&self) -> Option<&str> {
+        Some(&self.run_id)
+    }
+
+    async fn list_runs(&self) -> anyhow::Result<Vec<RunSummary>> {
+        let con = self.con.clone();
+        let counts = actix_web::rt::task::spawn_blocking(move || -> rusqlite::Result<Vec<(String, i64)>> {
+            let con = con.lock().unwrap();
+            let mut stmt = con.prepare("SELECT run_id, COUNT(*) FROM workers GROUP BY run_id ORDER BY run_id")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect()
+        }).await??;
+
+        Ok(counts.into_iter().map(|(run_id, workers)| RunSummary {
+            current: run_id == self.run_id,
+            run_id,
+            workers: workers as u64,
+            expires_in_secs: None,
+        }).collect())
+    }
+
+    async fn purge_run(&self, run_id: &str) -> anyhow::Result<u64> {
+        let con = self.con.clone();
+        let run_id = run_id.to_string();
+        let deleted = actix_web::rt::task::spawn_blocking(move || {
+            con.lock().unwrap().execute("DELETE FROM workers WHERE run_id = ?1", [run_id])
+        }).await??;
+        Ok(deleted as u64)
+    }
+}
+
+// scalar fields are stored as-is so they read well in redis-cli, nested reports as JSON
+fn to_fields(worker: &Worker) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("id", worker.id.clone()),
+        ("protocol_version", worker.protocol_version.to_string()),
+        ("status", worker.status.as_str().to_string()),
+    ];
+    if let Some(at) = worker.registered_at {
+        fields.push(("registered_at", at.to_rfc3339()));
+    }
+    if let Some(at) = worker.completed_at {
+        fields.push(("completed_at", at.to_rfc3339()));
+    }
+    if let Some(at) = worker.last_seen {
+        fields.push(("last_seen", at.to_rfc3339()));
+    }
+    if let Some(reason) = &worker.failure {
+        fields.push(("failure", reason.clone()));
+    }
+    if let Some(res) = &worker.results {
+        fields.push(("quantity", res.quantity.to_string()));
+        fields.push(("max_prime", res.max_prime.to_string()));
+    }
+    if let Some(digest) = &worker.result_digest {
+        fields.push(("result_digest", digest.clone()));
+    }
+    if worker.result_attempts > 0 {
+        fields.push(("result_attempts", worker.result_attempts.to_string()));
+    }
+    if let Some(start) = &worker.start {
+        fields.push(("start", serde_json::to_string(start).unwrap()));
+    }
+    if let Some(probe) = &worker.probe {
+        fields.push(("probe", serde_json::to_string(probe).unwrap()));
+    }
+    if let Some(kernel) = &worker.kernel {
+        fields.push(("kernel", serde_json::to_string(kernel).unwrap()));
+    }
+    fields
+}
+
+fn from_fields(fields: &HashMap<String, String>) -> anyhow::Result<Worker> {
+    let get = |name: &str| fields.get(name).ok_or_else(|| anyhow::anyhow!("missing field '{}'", name));
+    let time = |name: &str| fields.get(name).map(|v| chrono::DateTime::parse_from_rfc3339(v).map(|t| t.with_timezone(&chrono::Utc))).transpose();
+
+    let results = match (fields.get("quantity"), fields.get("max_prime")) {
+        (Some(q), Some(m)) => Some(PrimeResult { quantity: q.parse()?, max_prime: m.parse()? }),
+        _ => None,
+    };
+    Ok(Worker {
+        id: get("id")?.clone(),
+        protocol_version: get("protocol_version")?.parse()?,
+        status: get("status")?.parse::<WorkerStatus>().map_err(|e| anyhow::anyhow!(e))?,
+        registered_at: time("registered_at")?,
+        completed_at: time("completed_at")?,
+        last_seen: time("last_seen")?,
+        failure: fields.get("failure").cloned(),
+        start: json_field(fields, "start")?,
+        results,
+        probe: json_field(fields, "probe")?,
+        kernel: json_field(fields, "kernel")?,
+        result_digest: fields.get("result_digest").cloned(),
+        result_attempts: fields.get("result_attempts").map(|v| v.parse()).transpose()?.unwrap_or(0),
+    })
+}
+
+fn json_field<T: serde::de::DeserializeOwned>(fields: &HashMap<String, String>, name: &str) -> serde_json::Result<Option<T>> {
+    fields.get(name).map(|v| serde_json::from_str(v)).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sieve_protocol::{Kernel, KernelSummary};
+
+    #[test]
+    fn worker_survives_hash_round_trip() {
+        let worker = Worker {
+            protocol_version: 2,
+            status: WorkerStatus::Completed,
+            registered_at: Some("2021-11-20T10:00:00Z".parse().unwrap()),
+            completed_at: Some("2021-11-20T10:00:05Z".parse().unwrap()),
+            last_seen: Some("2021-11-20T10:00:05Z".parse().unwrap()),
+            results: Some(PrimeResult { quantity: 8, max_prime: 4294967477 }),
+            kernel: Some(KernelSummary {
+                kernel: Kernel::BitSieve,
+                input: 1000,
+                range_start: None,
+                operations: 3,
+                elapsed_micros: 40,
+                memory_bytes: Some(64),
+                value: None,
+            }),
+            result_digest: Some(String::from("9f2c")),
+            result_attempts: 2,
+            ..Worker::test("abc")
+        };
+
+        let fields: HashMap<String, String> = to_fields(&worker).into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        assert_eq!(fields["status"], "completed");
+        assert_eq!(fields["max_prime"], "4294967477");
+        assert_eq!(from_fields(&fields).unwrap(), worker);
+    }
+
+    #[test]
+    fn registered_worker_has_no_result_fields() {
+        let fields: HashMap<String, String> = [("id", "abc"), ("protocol_version", "1"), ("status", "registered")]
+            .into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let worker = from_fields(&fields).unwrap();
+        assert_eq!(worker.status, WorkerStatus::Registered);
+        assert!(worker.results.is_none() && worker.registered_at.is_none());
+    }
+
+    #[actix_web::test]
+    async fn sqlite_store_upserts_and_scopes_by_run() {
+        let path = std::env::temp_dir().join(format!("instance-service-test-{}.db", uuid::Uuid::new_v4()));
+        let store = SqliteStore::open(&path, String::from("run-a")).unwrap();
+        let other_run = SqliteStore::open(&path, String::from("run-b")).unwrap();
+
+        let mut worker = Worker {
+            protocol_version: 2,
+            registered_at: Some("2021-11-20T10:00:00Z".parse().unwrap()),
+            failure: Some(String::from("kernel input overflowed")),
+            ..Worker::test("abc")
+        };
+        store.save(&worker).await.unwrap();
+        worker.status = WorkerStatus::Completed;
+        worker.results = Some(PrimeResult { quantity: 8, max_prime: u64::MAX });
+        store.save(&worker).await.unwrap();
+
+        assert_eq!(store.load_all().await.unwrap(), vec![worker]);
+        assert!(other_run.load_all().await.unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn sqlite_store_lists_and_purges_runs() {
+        let path = std::env::temp_dir().join(format!("instance-service-test-{}.db", uuid::Uuid::new_v4()));
+        let store = SqliteStore::open(&path, String::from("run-a")).unwrap();
+        let other_run = SqliteStore::open(&path, String::from("run-b")).unwrap();
+        for (run, id) in [(&store, "a1"), (&store, "a2"), (&other_run, "b1")] {
+            run.save(&Worker::test(id)).await.unwrap();
+        }
+
+        let runs = store.list_runs().await.unwrap();
+        assert_eq!(runs, vec![
+            RunSummary { run_id: String::from("run-a"), workers: 2, expires_in_secs: None, current: true },
+            RunSummary { run_id: String::from("run-b"), workers: 1, expires_in_secs: None, current: false },
+        ]);
+
+        assert_eq!(store.purge_run("run-b").await.unwrap(), 1);
+        assert_eq!(store.purge_run("run-b").await.unwrap(), 0);
+        assert_eq!(store.list_runs().await.unwrap().len(), 1);
+        assert_eq!(store.load_all().await.unwrap().len(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_required_field_is_an_error() {
+        let fields: HashMap<String, String> = [("id", "abc")].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert!(from_fields(&fields).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use std::{thread::sleep, time::Duration, collections::BTreeMap};
 
 use actix_web::{App, HttpResponse, HttpServer, web};
-use k8s_openapi::api::{apps::v1::Deployment, core::v1::{Namespace, Pod, Service}};
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::{Namespace, Pod, Secret, Service}};
 use kube::{Api, Client, api::PostParams};
 use rand::{Rng, distributions::Alphanumeric, thread_rng};
 use serde::{Deserialize, Serialize};
@@ -11,8 +11,32 @@ use tracing_actix_web::TracingLogger;
 #[derive(Debug, Deserialize, Serialize)]
 struct WorkloadConfig {
     count: usize,
+    // optional latency probe settings handed to each sieve pod
+    probe_requests: Option<usize>,
+    probe_payload_bytes: Option<usize>,
+    probe_response_bytes: Option<usize>,
+    // optional start jitter/stagger settings handed to each sieve pod
+    jitter_distribution: Option<String>,
+    jitter_min_ms: Option<u64>,
+    jitter_max_ms: Option<u64>,
+    jitter_mean_ms: Option<u64>,
+    stagger_ms: Option<u64>,
+    // adds liveness/readiness probes against the sieve's health server
+    health_probes: Option<bool>,
+    // comma separated list of result sinks for the sieves (http, stdout, file, redis)
+    result_sinks: Option<String>,
+    // workload kernel for the sieves (sieve, bit-sieve, factor, lucas-lehmer, prime-count) and an optional fixed input size
+    kernel: Option<String>,
+    kernel_input: Option<u64>,
+    // start of a segmented window for the byte sieve, used to reach limits past 2^32
+    sieve_range_start: Option<u64>,
 }
 
+// port the sieve's health server listens on
+const SIEVE_HEALTH_PORT: u16 = 8081;
+// secret holding the shared key sieves sign their results with
+const RESULT_HMAC_SECRET: &str = "result-hmac";
+
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
     // init tracing logging
@@ -48,11 +72,13 @@ async fn init_workload(workload: web::Query<WorkloadConfig>) -> HttpResponse {
         }
     })).unwrap();
 
+    let mut linkerd_enabled = false;
     match std::env::var("LINKERD_INJECT") {
         Ok(val) => {
             if val == "true" {
                 tracing::info!("Linkerd injection is enabled - adding the correct annotation.");
                 add_inject_annotation_to_ns(&mut ns);
+                linkerd_enabled = true;
             }
         },
         Err(_) => {
@@ -83,7 +109,7 @@ async fn init_workload(workload: web::Query<WorkloadConfig>) -> HttpResponse {
         }
     }
 
-    if workload.count <= 0 {
+    if workload.count == 0 {
         tracing::warn!("Received request to spin up zero or a negative pod count - returning.");
         return HttpResponse::BadRequest().finish();
     }
@@ -93,7 +119,16 @@ async fn init_workload(workload: web::Query<WorkloadConfig>) -> HttpResponse {
     let instance_image_tag = std::env::var("INSTANCE_IMAGE").unwrap();
     let instance_image_url = format!("{}/{}", registry_url, instance_image_tag);
 
-    deploy_instance_service(client.clone(), &target_ns, &instance_image_url).await;
+    // the signing key is handed to both sides through a secret so it never shows up in the pod specs
+    let hmac_secret = match std::env::var("RESULT_HMAC_KEY") {
+        Ok(key) if !key.is_empty() => create_hmac_secret(client.clone(), &target_ns, &key).await,
+        _ => {
+            tracing::warn!("'RESULT_HMAC_KEY' variable not set - sieve results will not be signed.");
+            false
+        }
+    };
+
+    deploy_instance_service(client.clone(), &target_ns, &instance_image_url, hmac_secret).await;
 
     let pod_api: Api<Pod> = Api::namespaced(client.clone(), &target_ns);
 
@@ -105,37 +140,105 @@ async fn init_workload(workload: web::Query<WorkloadConfig>) -> HttpResponse {
     let sieve_image_tag = std::env::var("SIEVE_IMAGE").unwrap();
     let sieve_image_url = format!("{}/{}", registry_url, sieve_image_tag);
     
+    let mut sieve_env = vec![
+        json!({
+            "name": "RUST_LOG",
+            "value": "info"
+        }),
+        json!({
+            // sieve pods use restartPolicy Never, so the sieve has to stop the proxy sidecar itself
+            "name": "LINKERD_SHUTDOWN",
+            "value": linkerd_enabled.to_string()
+        }),
+        json!({
+            // lets the redis result sink file its records under the same run as instance service
+            "name": "RUN_ID",
+            "value": target_ns
+        }),
+    ];
+    let probe_settings = [
+        ("PROBE_REQUESTS", workload.probe_requests),
+        ("PROBE_PAYLOAD_BYTES", workload.probe_payload_bytes),
+        ("PROBE_RESPONSE_BYTES", workload.probe_response_bytes),
+    ];
+    for (name, value) in probe_settings {
+        if let Some(v) = value {
+            sieve_env.push(json!({ "name": name, "value": v.to_string() }));
+        }
+    }
+    if let Some(kernel) = &workload.kernel {
+        sieve_env.push(json!({ "name": "WORKLOAD_KERNEL", "value": kernel }));
+    }
+    if let Some(input) = workload.kernel_input {
+        sieve_env.push(json!({ "name": "KERNEL_INPUT", "value": input.to_string() }));
+    }
+    if let Some(range_start) = workload.sieve_range_start {
+        sieve_env.push(json!({ "name": "SIEVE_RANGE_START", "value": range_start.to_string() }));
+    }
+    if let Some(sinks) = &workload.result_sinks {
+        sieve_env.push(json!({ "name": "RESULT_SINKS", "value": sinks }));
+    }
+    if let Some(dist) = &workload.jitter_distribution {
+        sieve_env.push(json!({ "name": "START_JITTER_DISTRIBUTION", "value": dist }));
+    }
+    let jitter_settings = [
+        ("START_JITTER_MIN_MS", workload.jitter_min_ms),
+        ("START_JITTER_MAX_MS", workload.jitter_max_ms),
+        ("START_JITTER_MEAN_MS", workload.jitter_mean_ms),
+        ("START_STAGGER_MS", workload.stagger_ms),
+    ];
+    for (name, value) in jitter_settings {
+        if let Some(v) = value {
+            sieve_env.push(json!({ "name": name, "value": v.to_string() }));
+        }
+    }
+
+    if hmac_secret {
+        sieve_env.push(hmac_key_env());
+    }
+
     for n in 0..workload.count {
+        // the index lets each sieve work out its own stagger offset
+        let mut pod_env = sieve_env.clone();
+        pod_env.push(json!({ "name": "SIEVE_INDEX", "value": n.to_string() }));
+
+        let mut container = json!({
+            "env": pod_env,
+            "image": sieve_image_url,
+            "imagePullPolicy": "Always",
+            "name": "prime-generator",
+            "resources": {
+                "limits": {
+                    "cpu": "500m",
+                    "memory": "100Mi"
+                },
+                "requests": {
+                    "cpu": "100m",
+                    "memory": "50Mi"
+                }
+            }
+        });
+        if workload.health_probes.unwrap_or(false) {
+            add_sieve_health_probes(&mut container);
+        }
+
+        // linkerd only honours the sieve's POST to the proxy's /shutdown endpoint when the pod opts in
+        let annotations = if linkerd_enabled {
+            json!({ "config.linkerd.io/proxy-admin-shutdown": "enabled" })
+        } else {
+            json!({})
+        };
         let pod_def: Pod = serde_json::from_value(json!({
             "apiVersion": "v1",
             "kind": "Pod",
             "metadata": {
                 "name": format!("prime-sieve-instance-{}", n),
                 "namespace": target_ns,
+                "annotations": annotations,
             },
             "spec": {
                 "containers": [
-                    {
-                        "env": [
-                            {
-                                "name": "RUST_LOG",
-                                "value": "info"
-                            }
-                        ],
-                        "image": sieve_image_url,
-                        "imagePullPolicy": "Always",
-                        "name": "prime-generator",
-                        "resources": {
-                            "limits": {
-                                "cpu": "500m",
-                                "memory": "100Mi"
-                            },
-                            "requests": {
-                                "cpu": "100m",
-                                "memory": "50Mi"
-                            }
-                        }
-                    }
+                    container
                 ],
                 "restartPolicy": "Never"
 
@@ -180,13 +283,92 @@ fn add_inject_annotation_to_ns(ns: &mut Namespace) {
     tracing::debug!("Added 'linkerd.io/inject: enabled' to the namespace annotations.");
 }
 
+#[tracing::instrument(skip(container))]
+fn add_sieve_health_probes(container: &mut serde_json::Value) {
+    // with restartPolicy Never a failed liveness probe leaves the pod in a Failed phase, which is how
+    // a stuck sieve gets flagged instead of hanging around forever
+    container["ports"] = json!([
+        {
+            "containerPort": SIEVE_HEALTH_PORT,
+            "name": "health",
+            "protocol": "TCP"
+        }
+    ]);
+    container["livenessProbe"] = json!({
+        "failureThreshold": 3,
+        "httpGet": {
+            "path": "/healthz",
+            "port": SIEVE_HEALTH_PORT,
+            "scheme": "HTTP"
+        },
+        "initialDelaySeconds": 10,
+        "periodSeconds": 15,
+        "timeoutSeconds": 5
+    });
+    container["readinessProbe"] = json!({
+        "failureThreshold": 3,
+        "httpGet": {
+            "path": "/readyz",
+            "port": SIEVE_HEALTH_PORT,
+            "scheme": "HTTP"
+        },
+        "periodSeconds": 10,
+        "timeoutSeconds": 5
+    });
+    tracing::debug!("Added health probes on port {} to the sieve container.", SIEVE_HEALTH_PORT);
+}
+
+#[tracing::instrument(skip(client, key))]
+async fn create_hmac_secret(client: Client, target_ns: &str, key: &str) -> bool {
+    let secret_api: Api<Secret> = Api::namespaced(client, target_ns);
+    let secret: Secret = serde_json::from_value(json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": {
+            "name": RESULT_HMAC_SECRET,
+            "namespace": target_ns,
+        },
+        "type": "Opaque",
+        "stringData": {
+            "key": key
+        }
+    })).unwrap();
+
+    match secret_api.create(&PostParams::default(), &secret).await {
+        Ok(_) => {
+            tracing::debug!("Created result signing secret in target namespace '{}'", target_ns);
+            true
+        },
+        Err(kube::Error::Api(ae)) => {
+            tracing::warn!("Unable to create result signing secret - results will not be signed. Status: {}, message: {}", ae.status, ae.message);
+            false
+        },
+        Err(e) => {
+            tracing::error!("Unhandled error encountered: {:#?}", e);
+            false
+        }
+    }
+}
+
+fn hmac_key_env() -> serde_json::Value {
+    json!({
+        "name": "RESULT_HMAC_KEY",
+        "valueFrom": {
+            "secretKeyRef": {
+                "name": RESULT_HMAC_SECRET,
+                "key": "key"
+            }
+        }
+    })
+}
+
 #[tracing::instrument(skip(client))]
-async fn deploy_instance_service(client: Client, target_ns: &str, instance_image: &str) {
+async fn deploy_instance_service(client: Client, target_ns: &str, instance_image: &str, hmac_secret: bool) {
     // create instance service deployment and headless service in cluster
     let deploy_api: Api<Deployment> = Api::namespaced(client.clone(), target_ns);
     let service_api: Api<Service> = Api::namespaced(client.clone(), target_ns);
 
-    let dep: Deployment = serde_json::from_value(json!({
+    let mut dep_json = json!({
         "apiVersion": "apps/v1",
         "kind": "Deployment",
         "metadata": {
@@ -224,7 +406,15 @@ async fn deploy_instance_service(client: Client, target_ns: &str, instance_image
                                 },
                                 {
                                     "name": "REDIS_DB",
-                                    "value": "primes"
+                                    "value": "0"
+                                },
+                                {
+                                    "name": "WORKER_STORE",
+                                    "value": "redis"
+                                },
+                                {
+                                    "name": "RUN_ID",
+                                    "value": target_ns
                                 }
                             ],
                             "name": "instance-service",
@@ -232,7 +422,7 @@ async fn deploy_instance_service(client: Client, target_ns: &str, instance_image
                             "livenessProbe": {
                                 "failureThreshold": 5,
                                 "httpGet": {
-                                    "path": "/health",
+                                    "path": "/livez",
                                     "port": 8080,
                                     "scheme": "HTTP"
                                 }
@@ -245,7 +435,7 @@ async fn deploy_instance_service(client: Client, target_ns: &str, instance_image
                             "readinessProbe": {
                                 "failureThreshold": 5,
                                 "httpGet": {
-                                    "path": "/health",
+                                    "path": "/readyz",
                                     "port": 8080,
                                     "scheme": "HTTP"
                                 },
@@ -289,7 +479,13 @@ async fn deploy_instance_service(client: Client, target_ns: &str, instance_image
                 }
             }
         }
-    })).unwrap();
+    });
+    if hmac_secret {
+        if let Some(env) = dep_json["spec"]["template"]["spec"]["containers"][0]["env"].as_array_mut() {
+            env.push(hmac_key_env());
+        }
+    }
+    let dep: Deployment = serde_json::from_value(dep_json).unwrap();
 
     let headless: Service = serde_json::from_value(json!({
         "apiVersion": "v1",
@@ -0,0 +1,194 @@
+use crate::health::Progress;
+
+// small primes whose multiples are stamped in from a repeating pattern instead of being crossed off
+const PRESIEVE_PRIMES: [usize; 5] = [3, 5, 7, 11, 13];
+// 3 * 5 * 7 * 11 * 13 - the pattern period in odd-only bit positions
+const PRESIEVE_PERIOD: usize = 15015;
+
+/// Odd-only sieve packed into 64 bit words - bit `i` stands for the number `2i + 1`. Uses
+/// one sixteenth of the memory of the byte sieve.
+///
+/// The inner loops are written so the compiler can vectorize them: the pre-sieve fill is a
+/// straight word copy from a rotating pattern, primes below 64 are cleared a whole word at a
+/// time with precomputed masks and counting is a popcount over the words.
+pub struct BitSieve {
+    words: Vec<u64>,
+    limit: usize,
+}
+
+impl BitSieve {
+    pub fn new(limit: usize, progress: &Progress) -> BitSieve {
+        // odd numbers 1, 3, .., limit
+        let bits = limit.div_ceil(2);
+        let mut words = presieved_words(bits);
+
+        // 1 isn't prime, the pre-sieve primes were cleared along with their multiples
+        words[0] &= !1;
+        for p in PRESIEVE_PRIMES.iter().filter(|p| **p <= limit) {
+            set_bit(&mut words, p / 2);
+        }
+
+        let mut p = 17;
+        while p * p <= limit {
+            progress.tick();
+            if get_bit(&words, p / 2) {
+                let start = p * p / 2;
+                if p < 64 {
+                    clear_small_prime(&mut words, start, p);
+                } else {
+                    let mut idx = start;
+                    while idx < bits {
+                        words[idx / 64] &= !(1u64 << (idx % 64));
+                        idx += p;
+                    }
+                }
+            }
+            p += 2;
+        }
+
+        // trim anything past the limit in the last word
+        let last = words.len() - 1;
+        let tail = bits - last * 64;
+        if tail < 64 {
+            words[last] &= (1u64 << tail) - 1;
+        }
+
+        BitSieve { words, limit }
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.words.len() * std::mem::size_of::<u64>()
+    }
+
+    pub fn count(&self) -> usize {
+        if self.limit < 2 {
+            return 0;
+        }
+        // 2 isn't stored in the odd-only layout
+        1 + self.words.iter().map(|w| w.count_ones() as usize).sum::<usize>()
+    }
+
+    pub fn primes(&self) -> Vec<u64> {
+        let mut primes = Vec::with_capacity(self.count());
+        if self.limit >= 2 {
+            primes.push(2);
+        }
+        for (w, word) in self.words.iter().enumerate() {
+            let mut bits = *word;
+            while bits != 0 {
+                let tz = bits.trailing_zeros() as usize;
+                primes.push((2 * (w * 64 + tz) + 1) as u64);
+                bits &= bits - 1;
+            }
+        }
+        primes
+    }
+}
+
+// fills the sieve with the repeating pattern of odd numbers not divisible by the pre-sieve primes
+fn presieved_words(bits: usize) -> Vec<u64> {
+    // one period plus a spare word so any 64 bit window can be read without wrapping
+    let pattern_bits = PRESIEVE_PERIOD + 128;
+    let mut pattern = vec![u64::MAX; pattern_bits / 64 + 1];
+    for p in PRESIEVE_PRIMES {
+        let mut idx = p / 2;
+        while idx < pattern_bits {
+            pattern[idx / 64] &= !(1u64 << (idx % 64));
+            idx += p;
+        }
+    }
+
+    let word_count = bits.div_ceil(64).max(1);
+    let mut words = vec![0u64; word_count];
+    let mut offset = 0;
+    for word in words.iter_mut() {
+        let (i, shift) = (offset / 64, offset % 64);
+        *word = if shift == 0 {
+            pattern[i]
+        } else {
+            (pattern[i] >> shift) | (pattern[i + 1] << (64 - shift))
+        };
+        offset = (offset + 64) % PRESIEVE_PERIOD;
+    }
+    words
+}
+
+// clears every p-th bit starting at `start`, one word per step. within a word the bits to clear
+// only depend on the offset of the first one, so the p possible masks are built up front
+fn clear_small_prime(words: &mut [u64], start: usize, p: usize) {
+    let masks: Vec<u64> = (0..p)
+        .map(|r| (r..64).step_by(p).fold(0u64, |m, b| m | (1u64 << b)))
+        .collect();
+    let step = 64 % p;
+
+    let mut w = start / 64;
+    let first = start % 64;
+    // the first word can start past p, so build its mask by hand
+    let mut mask = 0u64;
+    let mut b = first;
+    while b < 64 {
+        mask |= 1u64 << b;
+        b += p;
+    }
+    words[w] &= !mask;
+    let mut r = b - 64;
+
+    w += 1;
+    while w < words.len() {
+        words[w] &= !masks[r];
+        r = (r + p - step) % p;
+        w += 1;
+    }
+}
+
+fn get_bit(words: &[u64], idx: usize) -> bool {
+    words[idx / 64] & (1u64 << (idx % 64)) != 0
+}
+
+fn set_bit(words: &mut [u64], idx: usize) {
+    words[idx / 64] |= 1u64 << (idx % 64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernels::segmented_sieve;
+
+    fn check(limit: usize, progress: &Progress) {
+        let sieve = BitSieve::new(limit, progress);
+        let expected = if limit < 2 { Vec::new() } else { segmented_sieve(0, limit as u64, progress) };
+        assert_eq!(sieve.primes(), expected, "limit {}", limit);
+        assert_eq!(sieve.count(), expected.len(), "limit {}", limit);
+    }
+
+    #[test]
+    fn matches_reference_for_tiny_limits() {
+        let progress = Progress::new(60000);
+        for limit in 0..=20 {
+            check(limit, &progress);
+        }
+    }
+
+    #[test]
+    fn matches_reference_around_word_boundaries() {
+        // bit 64k is the number 128k + 1, so these straddle the end of a word
+        let progress = Progress::new(60000);
+        for word in 1..=4 {
+            for limit in 128 * word - 3..=128 * word + 3 {
+                check(limit, &progress);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_reference_around_presieve_period() {
+        // 30030 = 2 * 3 * 5 * 7 * 11 * 13, where the pre-sieve pattern wraps round
+        let progress = Progress::new(60000);
+        for period in 1..=3 {
+            for limit in 30030 * period - 3..=30030 * period + 3 {
+                check(limit, &progress);
+            }
+        }
+        check(1_000_003, &progress);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{sync::{Arc, atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering}}, time::{SystemTime, UNIX_EPOCH}};
+
+use actix_web::{App, HttpResponse, HttpServer, web};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Starting,
+    Waiting,
+    Registering,
+    Probing,
+    Sieving,
+    Reporting,
+    Done,
+}
+
+impl Phase {
+    fn from_u8(v: u8) -> Phase {
+        match v {
+            1 => Phase::Waiting,
+            2 => Phase::Registering,
+            3 => Phase::Probing,
+            4 => Phase::Sieving,
+            5 => Phase::Reporting,
+            6 => Phase::Done,
+            _ => Phase::Starting,
+        }
+    }
+}
+
+/// Shared between the sieve and the health server. The sieve bumps it as it works and the
+/// server compares the last tick against the stall threshold.
+#[derive(Debug)]
+pub struct Progress {
+    phase: AtomicU8,
+    ticks: AtomicU64,
+    last_tick_ms: AtomicU64,
+    registered: AtomicBool,
+    stall_threshold_ms: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct HealthStatus {
+    phase: Phase,
+    ticks: u64,
+    ms_since_progress: u64,
+    stall_threshold_ms: u64,
+    registered: bool,
+}
+
+impl Progress {
+    pub fn new(stall_threshold_ms: u64) -> Progress {
+        Progress {
+            phase: AtomicU8::new(Phase::Starting as u8),
+            ticks: AtomicU64::new(0),
+            last_tick_ms: AtomicU64::new(now_ms()),
+            registered: AtomicBool::new(false),
+            stall_threshold_ms,
+        }
+    }
+
+    pub fn set_phase(&self, phase: Phase) {
+        tracing::debug!("Sieve entering phase {:?}", phase);
+        self.phase.store(phase as u8, Ordering::Relaxed);
+        self.tick();
+    }
+
+    pub fn tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.last_tick_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub fn set_registered(&self) {
+        self.registered.store(true, Ordering::Relaxed);
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::from_u8(self.phase.load(Ordering::Relaxed))
+    }
+
+    fn status(&self) -> HealthStatus {
+        HealthStatus {
+            phase: self.phase(),
+            ticks: self.ticks.load(Ordering::Relaxed),
+            ms_since_progress: now_ms().saturating_sub(self.last_tick_ms.load(Ordering::Relaxed)),
+            stall_threshold_ms: self.stall_threshold_ms,
+            registered: self.registered.load(Ordering::Relaxed),
+        }
+    }
+
+    fn is_stalled(&self, status: &HealthStatus) -> bool {
+        // deliberate sleeps (start delays) are allowed to run past the threshold
+        status.phase != Phase::Waiting && status.ms_since_progress > self.stall_threshold_ms
+    }
+}
+
+/// Starts the health server on a background task and hands back the shared progress tracker.
+pub fn start_health_server() -> anyhow::Result<Arc<Progress>> {
+    let port = match std::env::var("HEALTH_PORT") {
+        Ok(val) => val.parse::<u16>().map_err(|e| anyhow::anyhow!("Invalid HEALTH_PORT '{}': {}", val, e))?,
+        Err(_) => 8081,
+    };
+    let stall_threshold_ms = match std::env::var("HEALTH_STALL_THRESHOLD_MS") {
+        Ok(val) => val.parse::<u64>().map_err(|e| anyhow::anyhow!("Invalid HEALTH_STALL_THRESHOLD_MS '{}': {}", val, e))?,
+        Err(_) => 60000,
+    };
+
+    let progress = Arc::new(Progress::new(stall_threshold_ms));
+    let data = web::Data::from(progress.clone());
+
+    let server = HttpServer::new(move || {
+    App::new()
+        .app_data(data.clone())
+        .route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("0.0.0.0", port))?
+    .run();
+
+    tokio::spawn(server);
+    tracing::info!("Health server listening on port {}", port);
+
+    Ok(progress)
+}
+
+async fn healthz(progress: web::Data<Progress>) -> HttpResponse {
+    let status = progress.status();
+    if progress.is_stalled(&status) {
+        tracing::warn!("No sieve progress for {}ms in phase {:?} - reporting unhealthy.", status.ms_since_progress, status.phase);
+        HttpResponse::ServiceUnavailable().json(status)
+    } else {
+        HttpResponse::Ok().json(status)
+    }
+}
+
+async fn readyz(progress: web::Data<Progress>) -> HttpResponse {
+    let status = progress.status();
+    if status.registered {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::ServiceUnavailable().json(status)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
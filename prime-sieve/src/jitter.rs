@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use rand::Rng;
+use sieve_protocol::{JitterDistribution, StartInfo};
+
+#[derive(Debug, Clone)]
+pub struct JitterConfig {
+    pub distribution: JitterDistribution,
+    pub min_ms: u64,
+    pub max_ms: u64,
+    pub mean_ms: u64,
+    pub stagger_ms: u64,
+    pub index: u64,
+}
+
+impl JitterConfig {
+    pub fn from_env() -> anyhow::Result<JitterConfig> {
+        let distribution = match std::env::var("START_JITTER_DISTRIBUTION") {
+            Ok(val) => val.parse::<JitterDistribution>()
+                .map_err(|e| anyhow::anyhow!("Invalid START_JITTER_DISTRIBUTION: {}", e))?,
+            Err(_) => JitterDistribution::None,
+        };
+
+        let config = JitterConfig {
+            distribution,
+            min_ms: env_u64("START_JITTER_MIN_MS", 0)?,
+            max_ms: env_u64("START_JITTER_MAX_MS", 10000)?,
+            mean_ms: env_u64("START_JITTER_MEAN_MS", 2000)?,
+            stagger_ms: env_u64("START_STAGGER_MS", 0)?,
+            index: env_u64("SIEVE_INDEX", 0)?,
+        };
+
+        if config.min_ms > config.max_ms {
+            return Err(anyhow::anyhow!("START_JITTER_MIN_MS ({}) is larger than START_JITTER_MAX_MS ({})", config.min_ms, config.max_ms));
+        }
+        Ok(config)
+    }
+
+    /// Picks the random part of the start delay from the configured distribution, in milliseconds.
+    /// Exponential samples are capped at `max_ms` so a single unlucky draw can't stall a run.
+    pub fn sample_jitter_ms(&self) -> u64 {
+        let mut rng = rand::thread_rng();
+        match self.distribution {
+            JitterDistribution::None => 0,
+            JitterDistribution::Uniform => rng.gen_range(self.min_ms..=self.max_ms),
+            JitterDistribution::Exponential => {
+                // inverse transform sampling - gen::<f64>() is in [0, 1) so the log never sees zero
+                let u: f64 = rng.gen();
+                let sample = -(self.mean_ms as f64) * (1.0 - u).ln();
+                (sample as u64).min(self.max_ms)
+            }
+        }
+    }
+
+    pub fn stagger_delay_ms(&self) -> u64 {
+        self.index.saturating_mul(self.stagger_ms)
+    }
+}
+
+/// Sleeps for the stagger offset plus a sampled jitter and reports what was chosen.
+pub async fn delay_start(config: &JitterConfig) -> StartInfo {
+    let jitter_ms = config.sample_jitter_ms();
+    let stagger_ms = config.stagger_delay_ms();
+    // the stagger saturates for huge indexes, so the total has to as well
+    let delay_ms = jitter_ms.saturating_add(stagger_ms);
+    tracing::info!("Delaying start by {}ms ({:?} jitter {}ms + stagger {}ms for index {})",
+        delay_ms, config.distribution, jitter_ms, stagger_ms, config.index);
+
+    tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+
+    let started_at = Utc::now();
+    tracing::info!("Sieve work starting at {}", started_at.to_rfc3339());
+    StartInfo {
+        distribution: config.distribution,
+        jitter_ms,
+        stagger_ms,
+        started_at,
+    }
+}
+
+fn env_u64(name: &str, default: u64) -> anyhow::Result<u64> {
+    match std::env::var(name) {
+        Ok(val) => val.parse::<u64>()
+            .map_err(|e| anyhow::anyhow!("Invalid value '{}' for {}: {}", val, name, e)),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(distribution: JitterDistribution) -> JitterConfig {
+        JitterConfig { distribution, min_ms: 100, max_ms: 300, mean_ms: 2000, stagger_ms: 0, index: 0 }
+    }
+
+    #[test]
+    fn uniform_jitter_stays_in_bounds() {
+        let cfg = config(JitterDistribution::Uniform);
+        let samples: Vec<u64> = (0..1000).map(|_| cfg.sample_jitter_ms()).collect();
+        assert!(samples.iter().all(|s| (100..=300).contains(s)), "{:?}", samples);
+
+        let pinned = JitterConfig { min_ms: 250, max_ms: 250, ..cfg };
+        assert_eq!(pinned.sample_jitter_ms(), 250);
+        assert_eq!(config(JitterDistribution::None).sample_jitter_ms(), 0);
+    }
+
+    #[test]
+    fn exponential_jitter_is_capped_at_max() {
+        // a mean far above the cap puts most draws on it
+        let cfg = config(JitterDistribution::Exponential);
+        let samples: Vec<u64> = (0..1000).map(|_| cfg.sample_jitter_ms()).collect();
+        assert!(samples.iter().all(|s| *s <= 300), "{:?}", samples);
+        assert!(samples.iter().filter(|s| **s == 300).count() > 500);
+    }
+
+    #[test]
+    fn stagger_is_index_times_step() {
+        let cfg = JitterConfig { stagger_ms: 250, index: 7, ..config(JitterDistribution::None) };
+        assert_eq!(cfg.stagger_delay_ms(), 1750);
+        let cfg = JitterConfig { index: 0, ..cfg };
+        assert_eq!(cfg.stagger_delay_ms(), 0);
+        let cfg = JitterConfig { stagger_ms: u64::MAX, index: 3, ..cfg };
+        assert_eq!(cfg.stagger_delay_ms(), u64::MAX);
+    }
+
+    #[tokio::test]
+    async fn saturated_stagger_plus_jitter_does_not_overflow() {
+        let cfg = JitterConfig { min_ms: 50, max_ms: 50, stagger_ms: u64::MAX, index: 3, ..config(JitterDistribution::Uniform) };
+        // the delay is effectively forever, so all we can check is that it starts sleeping without panicking
+        let res = tokio::time::timeout(Duration::from_millis(20), delay_start(&cfg)).await;
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,529 @@
+use std::{collections::HashMap, time::Instant};
+
+use num_bigint::BigUint;
+use rand::Rng;
+use sieve_protocol::{Kernel, KernelSummary};
+
+use crate::health::Progress;
+
+/// Reads the workload kernel from `WORKLOAD_KERNEL`, defaulting to the byte sieve.
+pub fn kernel_from_env() -> anyhow::Result<Kernel> {
+    match std::env::var("WORKLOAD_KERNEL") {
+        Ok(val) => val.parse::<Kernel>().map_err(|e| anyhow::anyhow!("Invalid WORKLOAD_KERNEL: {}", e)),
+        Err(_) => Ok(Kernel::Sieve),
+    }
+}
+
+/// Output of one of the ALU-bound kernels: the primes to report plus the run summary.
+pub struct KernelOutput {
+    pub primes: Vec<u64>,
+    pub summary: KernelSummary,
+}
+
+/// Factors `count` random semiprimes (two ~31 bit primes each) with Pollard's rho and reports
+/// the distinct prime factors found.
+pub fn factor_semiprimes(count: u64, progress: &Progress) -> KernelOutput {
+    let start = Instant::now();
+    let mut rng = rand::thread_rng();
+    let mut operations = 0;
+    let mut factors: Vec<u64> = Vec::with_capacity(count as usize * 2);
+
+    for _ in 0..count {
+        progress.tick();
+        let p = random_prime(&mut rng, 1 << 30, 1 << 31);
+        let q = random_prime(&mut rng, 1 << 30, 1 << 31);
+        let n = p * q;
+
+        let (d, iters) = pollard_rho(n, &mut rng);
+        operations += iters;
+        factors.push(d);
+        factors.push(n / d);
+    }
+    factors.sort_unstable();
+    factors.dedup();
+
+    KernelOutput {
+        primes: factors,
+        summary: KernelSummary {
+            kernel: Kernel::Factor,
+            input: count,
+            range_start: None,
+            operations,
+            elapsed_micros: start.elapsed().as_micros() as u64,
+            memory_bytes: None,
+            value: None,
+        },
+    }
+}
+
+/// Runs the Lucas-Lehmer test on every prime exponent up to `max_exponent` and reports the
+/// exponents whose Mersenne number is prime.
+pub fn lucas_lehmer_scan(max_exponent: u64, progress: &Progress) -> KernelOutput {
+    let start = Instant::now();
+    let mut operations = 0;
+    let mut exponents = Vec::new();
+
+    for p in 2..=max_exponent {
+        if !is_prime_u64(p) {
+            continue;
+        }
+        progress.tick();
+        operations += p.saturating_sub(2);
+        if lucas_lehmer(p) {
+            tracing::debug!("Found Mersenne prime 2^{} - 1", p);
+            exponents.push(p);
+        }
+    }
+
+    KernelOutput {
+        primes: exponents,
+        summary: KernelSummary {
+            kernel: Kernel::LucasLehmer,
+            input: max_exponent,
+            range_start: None,
+            operations,
+            elapsed_micros: start.elapsed().as_micros() as u64,
+            memory_bytes: None,
+            value: None,
+        },
+    }
+}
+
+/// Smallest input the prime-count kernel accepts. Below 4 the small-prime table (primes up to
+/// the square root) is empty, so the result would carry no primes and instance service rejects it.
+pub const MIN_PRIME_COUNT_INPUT: u64 = 4;
+
+pub fn check_prime_count_input(x: u64) -> anyhow::Result<()> {
+    if x < MIN_PRIME_COUNT_INPUT {
+        anyhow::bail!("prime-count needs KERNEL_INPUT of at least {} so the result has a prime to report, got {}",
+            MIN_PRIME_COUNT_INPUT, x);
+    }
+    Ok(())
+}
+
+/// Counts the primes up to `x` with the Meissel-Lehmer method. The reported primes are the
+/// ones sieved for the small-prime table; the count itself goes in the summary.
+pub fn count_primes(x: u64, progress: &Progress) -> KernelOutput {
+    let start = Instant::now();
+    let mut counter = PrimeCounter::new(x, progress);
+    let count = counter.pi(x);
+
+    let table_limit = isqrt(x);
+    let primes = counter.primes.iter()
+        .take_while(|p| **p <= table_limit)
+        .copied()
+        .collect();
+
+    KernelOutput {
+        primes,
+        summary: KernelSummary {
+            kernel: Kernel::PrimeCount,
+            input: x,
+            range_start: None,
+            operations: counter.phi_calls,
+            elapsed_micros: start.elapsed().as_micros() as u64,
+            memory_bytes: None,
+            value: Some(count),
+        },
+    }
+}
+
+// numbers covered by each window of the segmented sieve - one byte per number, sized to stay in cache
+pub const SEGMENT_SIZE: usize = 1 << 18;
+
+/// Byte sieve over `low..=high` processed one cache-sized window at a time, so only the base
+/// primes up to sqrt(high) and a single segment are ever held in memory.
+pub fn segmented_sieve(low: u64, high: u64, progress: &Progress) -> Vec<u64> {
+    let low = low.max(2);
+    if high < low {
+        return Vec::new();
+    }
+
+    let base_limit = isqrt(high) as usize;
+    let mut is_base_prime = vec![true; base_limit + 1];
+    let mut base_primes: Vec<u64> = Vec::new();
+    for i in 2..=base_limit {
+        if is_base_prime[i] {
+            base_primes.push(i as u64);
+            let mut multiple = i * i;
+            while multiple <= base_limit {
+                is_base_prime[multiple] = false;
+                multiple += i;
+            }
+        }
+    }
+
+    let mut primes = Vec::new();
+    let mut segment = vec![true; SEGMENT_SIZE];
+    let mut seg_low = low;
+    loop {
+        progress.tick();
+        let seg_high = seg_low.saturating_add(SEGMENT_SIZE as u64 - 1).min(high);
+        let len = (seg_high - seg_low + 1) as usize;
+        segment[..len].fill(true);
+
+        for p in &base_primes {
+            if p * p > seg_high {
+                break;
+            }
+            // first multiple of p inside the window, never p itself
+            let mut multiple = (p * p).max(seg_low.div_ceil(*p) * p);
+            while multiple <= seg_high {
+                segment[(multiple - seg_low) as usize] = false;
+                multiple += p;
+            }
+        }
+
+        primes.extend(segment[..len].iter()
+            .enumerate()
+            .filter_map(|(i, is_prime)| if *is_prime { Some(seg_low + i as u64) } else { None }));
+
+        if seg_high == high {
+            break;
+        }
+        seg_low = seg_high + 1;
+    }
+    primes
+}
+
+fn pollard_rho<R: Rng>(n: u64, rng: &mut R) -> (u64, u64) {
+    if n.is_multiple_of(2) {
+        return (2, 0);
+    }
+
+    let mut iterations = 0;
+    loop {
+        let c = rng.gen_range(1..n);
+        let f = |v: u64| (mul_mod(v, v, n) + c) % n;
+        let mut x = rng.gen_range(0..n);
+        let mut y = x;
+        let mut d = 1;
+
+        while d == 1 {
+            x = f(x);
+            y = f(f(y));
+            d = gcd(x.abs_diff(y), n);
+            iterations += 1;
+        }
+        // a cycle without a split just means a bad choice of c - try again
+        if d != n {
+            return (d, iterations);
+        }
+    }
+}
+
+fn lucas_lehmer(p: u64) -> bool {
+    if p == 2 {
+        return true;
+    }
+
+    let m = (BigUint::from(1u32) << p) - 1u32;
+    let mut s = BigUint::from(4u32);
+    for _ in 0..p - 2 {
+        // add m before subtracting 2 so the value never goes negative
+        s = &s * &s + &m - 2u32;
+        // reduce mod 2^p - 1 by folding the high bits onto the low bits
+        while s.bits() > p {
+            s = (&s & &m) + (&s >> p);
+        }
+        if s >= m {
+            s -= &m;
+        }
+    }
+    s.bits() == 0
+}
+
+// below this bound pi is answered straight from the sieve table
+const SMALL_PI_LIMIT: u64 = 1 << 20;
+// phi results are memoized for arguments under this bound
+const PHI_CACHE_LIMIT: u64 = 1 << 16;
+
+struct PrimeCounter<'a> {
+    primes: Vec<u64>,
+    small_pi: Vec<u32>,
+    phi_cache: HashMap<(u64, usize), u64>,
+    phi_calls: u64,
+    progress: &'a Progress,
+}
+
+impl<'a> PrimeCounter<'a> {
+    fn new(x: u64, progress: &'a Progress) -> PrimeCounter<'a> {
+        let limit = (isqrt(x) + 1).max(SMALL_PI_LIMIT) as usize;
+        let mut is_prime = vec![true; limit + 1];
+        is_prime[0] = false;
+        is_prime[1] = false;
+        let mut i = 2;
+        while i * i <= limit {
+            if is_prime[i] {
+                let mut multiple = i * i;
+                while multiple <= limit {
+                    is_prime[multiple] = false;
+                    multiple += i;
+                }
+            }
+            i += 1;
+        }
+
+        let mut primes = Vec::new();
+        let mut small_pi = Vec::with_capacity(limit + 1);
+        let mut running = 0;
+        for (n, p) in is_prime.into_iter().enumerate() {
+            if p {
+                primes.push(n as u64);
+                running += 1;
+            }
+            small_pi.push(running);
+        }
+
+        PrimeCounter { primes, small_pi, phi_cache: HashMap::new(), phi_calls: 0, progress }
+    }
+
+    // Lehmer's formula
+    fn pi(&mut self, x: u64) -> u64 {
+        if (x as usize) < self.small_pi.len() {
+            return self.small_pi[x as usize] as u64;
+        }
+
+        let a = self.pi(iroot(x, 4)) as usize;
+        let b = self.pi(isqrt(x)) as usize;
+        let c = self.pi(iroot(x, 3)) as usize;
+
+        let mut sum = self.phi(x, a) + ((b + a - 2) * (b - a + 1) / 2) as u64;
+        for i in (a + 1)..=b {
+            self.progress.tick();
+            let w = x / self.primes[i - 1];
+            sum -= self.pi(w);
+            if i <= c {
+                let bi = self.pi(isqrt(w)) as usize;
+                for j in i..=bi {
+                    sum -= self.pi(w / self.primes[j - 1]) - (j as u64 - 1);
+                }
+            }
+        }
+        sum
+    }
+
+    // count of integers <= x not divisible by any of the first a primes
+    fn phi(&mut self, x: u64, a: usize) -> u64 {
+        self.phi_calls += 1;
+        if a == 0 {
+            return x;
+        }
+        if x == 0 {
+            return 0;
+        }
+        if self.primes[a - 1] >= x {
+            return 1;
+        }
+        if x < PHI_CACHE_LIMIT {
+            if let Some(v) = self.phi_cache.get(&(x, a)) {
+                return *v;
+            }
+        }
+
+        let v = self.phi(x, a - 1) - self.phi(x / self.primes[a - 1], a - 1);
+        if x < PHI_CACHE_LIMIT {
+            self.phi_cache.insert((x, a), v);
+        }
+        v
+    }
+}
+
+fn random_prime<R: Rng>(rng: &mut R, low: u64, high: u64) -> u64 {
+    loop {
+        let candidate = rng.gen_range(low..high) | 1;
+        if is_prime_u64(candidate) {
+            return candidate;
+        }
+    }
+}
+
+// deterministic Miller-Rabin for the full u64 range
+fn is_prime_u64(n: u64) -> bool {
+    const BASES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+    if n < 2 {
+        return false;
+    }
+    for p in BASES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+
+    let mut d = n - 1;
+    let mut r = 0;
+    while d.is_multiple_of(2) {
+        d /= 2;
+        r += 1;
+    }
+
+    'witness: for a in BASES {
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..r {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    ((a as u128 * b as u128) % m as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    result
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a
+}
+
+fn isqrt(x: u64) -> u64 {
+    iroot(x, 2)
+}
+
+// integer k-th root, corrected for floating point error
+fn iroot(x: u64, k: u32) -> u64 {
+    let mut r = (x as f64).powf(1.0 / k as f64) as u64;
+    while r > 0 && r.checked_pow(k).is_none_or(|v| v > x) {
+        r -= 1;
+    }
+    while (r + 1).checked_pow(k).is_some_and(|v| v <= x) {
+        r += 1;
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segmented_sieve_above_u32() {
+        let progress = Progress::new(60000);
+        let low = 1u64 << 32;
+        let primes = segmented_sieve(low, low + 200, &progress);
+        assert_eq!(primes, vec![4294967311, 4294967357, 4294967371, 4294967377, 4294967387, 4294967389, 4294967459, 4294967477]);
+    }
+
+    #[test]
+    fn segmented_sieve_spans_segments() {
+        // crosses several segment boundaries well past 2^32 - every hit must pass Miller-Rabin and
+        // every Miller-Rabin prime in the window must be found
+        let progress = Progress::new(60000);
+        let low = (1u64 << 33) + 12345;
+        let high = low + 3 * SEGMENT_SIZE as u64 + 17;
+        let primes = segmented_sieve(low, high, &progress);
+        let expected: Vec<u64> = (low..=high).filter(|n| is_prime_u64(*n)).collect();
+        assert_eq!(primes, expected);
+    }
+
+    #[test]
+    fn segmented_sieve_matches_small_range() {
+        let progress = Progress::new(60000);
+        let primes = segmented_sieve(0, 100, &progress);
+        assert_eq!(primes, vec![2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97]);
+    }
+
+    #[test]
+    fn result_payload_keeps_u64_primes() {
+        let progress = Progress::new(60000);
+        let low = 5_000_000_000u64;
+        let primes = segmented_sieve(low, low + 1000, &progress);
+        let body = serde_json::to_string(&primes).unwrap();
+        let parsed: Vec<u64> = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed, primes);
+        assert!(parsed.iter().all(|p| *p > u32::MAX as u64));
+    }
+
+    #[test]
+    fn is_prime_u64_known_answers() {
+        let small: Vec<u64> = (0..100).filter(|n| is_prime_u64(*n)).collect();
+        assert_eq!(small, segmented_sieve(0, 100, &Progress::new(60000)));
+
+        // largest primes below 2^32 and 2^64, and the first above 2^32
+        for p in [4294967291, 4294967311, 18446744073709551557] {
+            assert!(is_prime_u64(p), "{}", p);
+        }
+        // Carmichael numbers, strong pseudoprimes to the first bases, and the edges of u64
+        for n in [561, 1105, 1729, 3215031751, 3825123056546413051, 18446744073709551615, 4294967297] {
+            assert!(!is_prime_u64(n), "{}", n);
+        }
+    }
+
+    #[test]
+    fn pollard_rho_splits_semiprimes() {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        assert_eq!(pollard_rho(2 * 1_000_003, &mut rng).0, 2);
+        for _ in 0..20 {
+            let p = random_prime(&mut rng, 1 << 30, 1 << 31);
+            let q = random_prime(&mut rng, 1 << 30, 1 << 31);
+            let n = p * q;
+            let (d, _) = pollard_rho(n, &mut rng);
+            assert!(is_prime_u64(d) && is_prime_u64(n / d), "{} = {} * {}", n, d, n / d);
+            assert_eq!(d * (n / d), n);
+            assert!(d == p || d == q, "{} is not one of {} and {}", d, p, q);
+        }
+    }
+
+    #[test]
+    fn factor_semiprimes_reports_primes_in_range() {
+        let out = factor_semiprimes(10, &Progress::new(60000));
+        assert_eq!(out.summary.kernel, Kernel::Factor);
+        assert!(!out.primes.is_empty() && out.primes.len() <= 20);
+        assert!(out.primes.windows(2).all(|w| w[0] < w[1]));
+        assert!(out.primes.iter().all(|p| is_prime_u64(*p) && (1 << 30..1 << 31).contains(p)));
+    }
+
+    #[test]
+    fn lucas_lehmer_finds_mersenne_exponents() {
+        let out = lucas_lehmer_scan(1279, &Progress::new(60000));
+        assert_eq!(out.primes, vec![2, 3, 5, 7, 13, 17, 19, 31, 61, 89, 107, 127, 521, 607, 1279]);
+    }
+
+    #[test]
+    fn count_primes_matches_known_pi() {
+        let progress = Progress::new(60000);
+        let known = [(10, 4), (100, 25), (1_000, 168), (10_000, 1_229), (100_000, 9_592), (1_000_000, 78_498), (10_000_000, 664_579), (100_000_000, 5_761_455)];
+        for (x, pi) in known {
+            assert_eq!(count_primes(x, &progress).summary.value, Some(pi), "pi({})", x);
+        }
+        // just either side of a prime
+        assert_eq!(count_primes(1_048_583, &progress).summary.value, Some(82_026));
+        assert_eq!(count_primes(1_048_582, &progress).summary.value, Some(82_025));
+    }
+
+    #[test]
+    fn prime_count_rejects_inputs_without_table_primes() {
+        let progress = Progress::new(60000);
+        for x in 0..4 {
+            assert!(check_prime_count_input(x).is_err(), "x = {}", x);
+            assert!(count_primes(x, &progress).primes.is_empty(), "x = {}", x);
+        }
+        assert!(check_prime_count_input(4).is_ok());
+        assert_eq!(count_primes(4, &progress).primes, vec![2]);
+    }
+}
@@ -1,35 +1,59 @@
-use std::{net::IpAddr, time::Duration};
+use std::{net::IpAddr, sync::Arc, time::{Duration, Instant}};
 
 use rand::Rng;
-use reqwest::{Response, StatusCode};
-use serde::{Deserialize, Serialize};
+use sieve_protocol::{FailurePayload, HeartbeatPayload, Kernel, KernelSummary, RegisterPayload, ResultPayload, PROTOCOL_VERSION};
 use tokio::time::sleep;
 use trust_dns_resolver::AsyncResolver;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct RegisterPayload {
-    id: String,
-}
+use health::{Phase, Progress};
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ResultPayload {
-    id: String,
-    primes: Vec<usize>,
-}
+mod bitsieve;
+mod health;
+mod jitter;
+mod kernels;
+mod probe;
+mod sink;
+
+const INSTANCE_SERVICE_URL: &str = "http://instance-service-headless:8080";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // derive all primes up to a random number of primes
     // first we create our logger, then register with the instance service
-    tracing_subscriber::fmt::init();
+    // the stdout sink writes one JSON record per line, so logs go to stderr instead of mixing in
+    if sink::stdout_sink_selected() {
+        tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+    } else {
+        tracing_subscriber::fmt::init();
+    }
+
+    let res = run_sieve().await;
+
+    // when running inside the mesh the proxy sidecar outlives us unless told to stop, which keeps
+    // the pod from ever reaching a completed phase - so always signal it on the way out
+    if linkerd_shutdown_enabled() {
+        shutdown_linkerd_proxy().await;
+    }
+
+    res
+}
+
+async fn run_sieve() -> anyhow::Result<()> {
+    let jitter_config = jitter::JitterConfig::from_env()?;
+    let kernel = kernels::kernel_from_env()?;
+    let probe_config = probe::ProbeConfig::from_env()?;
+    let hmac_key = sieve_protocol::integrity::key_from_env()
+        .map_err(|e| anyhow::anyhow!("Unable to load result signing key: {}", e))?;
+    if hmac_key.is_none() {
+        tracing::info!("No RESULT_HMAC_KEY configured - results will carry a digest but no signature.");
+    }
+    let progress = health::start_health_server()?;
 
     let mut buf = uuid::Uuid::encode_buffer();
     let sieve_id = String::from(uuid::Uuid::new_v4().to_hyphenated().encode_lower(&mut buf));
     tracing::debug!("Sieve ID generated for this instance: {}", sieve_id);
-    let register = RegisterPayload {
-        id: sieve_id.clone(),
-    };
 
+    progress.set_phase(Phase::Waiting);
     sleep(Duration::from_millis(5000)).await;
 
     // test DNS resolution twice for instance service and then proceed
@@ -43,61 +67,239 @@ async fn main() -> anyhow::Result<()> {
     tracing::debug!("Going to sleep for 20 seconds in an attempt to allow instance-service to come up.");
     sleep(Duration::from_millis(20000)).await;
 
-    // build http client and send the register request to instance service
+    // spread (or deliberately bunch up) the fleet before it hits instance service
+    let start = jitter::delay_start(&jitter_config).await;
+    let register = RegisterPayload {
+        protocol_version: PROTOCOL_VERSION,
+        id: sieve_id.clone(),
+        start: Some(start),
+    };
+
+    // build http client and send the register request to every configured sink
+    progress.set_phase(Phase::Registering);
     tracing::debug!("Creating HTTP client to interact with instance service");
     let client = reqwest::Client::new();
-    let resp: Response = client.post("http://instance-service-headless:8080/register")
-        .header("content-type", "application/json")
-        .json(&register)
-        .send()
-        .await?;
+    let sinks = Arc::new(sink::sinks_from_env(&client, INSTANCE_SERVICE_URL, hmac_key.as_deref())?);
 
-    if resp.status() == StatusCode::CREATED {
-        tracing::info!("Registered sieve worker with instance service, starting prime generation.");
-    } else {
-        tracing::warn!("Failed to register with instance sercice. Status code '{}' - continuing with work.", resp.status().as_u16());
+    for s in sinks.iter() {
+        match s.register(&register).await {
+            Ok(()) => {
+                tracing::info!("Registered sieve worker with '{}' sink.", s.name());
+                progress.set_registered();
+            },
+            Err(e) => {
+                tracing::warn!("Failed to register with '{}' sink - continuing with work. Error: {:?}", s.name(), e);
+            }
+        }
     }
 
+    let heartbeats = start_heartbeats(sinks.clone(), &sieve_id)?;
+
+    // optionally measure the network path to instance service before doing any real work
+    let probe_summary = match probe_config {
+        Some(cfg) => {
+            progress.set_phase(Phase::Probing);
+            Some(probe::run_probe(&client, INSTANCE_SERVICE_URL, &cfg, &progress).await)
+        },
+        None => None,
+    };
+
     // once registered, we start calculating primes
-    let n = rand::thread_rng().gen_range(100000..=2500000);
-    tracing::info!("Generating primes up to a limit of {}", n);
-    let res = basic_sieve(n).await.collect::<Vec<_>>();
-    tracing::info!("Generated prime number payload with {} entries. Building and sending results to instance service.", res.len());
+    progress.set_phase(Phase::Sieving);
+    // the kernels never yield, so they run on the blocking pool - a sieve pod capped below one CPU
+    // gets a single runtime worker, and heartbeats and the health server need it
+    let kernel_progress = progress.clone();
+    let kernel_result = tokio::task::spawn_blocking(move || run_kernel(kernel, &kernel_progress)).await
+        .map_err(|e| anyhow::anyhow!("Kernel task did not finish: {}", e))
+        .and_then(|res| res);
+    if let Some(h) = heartbeats {
+        h.abort();
+    }
+    let (res, kernel_summary) = match kernel_result {
+        Ok(out) => out,
+        Err(e) => {
+            report_failure(&sinks, &sieve_id, &e).await;
+            progress.set_phase(Phase::Done);
+            return Err(e);
+        }
+    };
+    tracing::info!("Generated prime number payload with {} entries in {}us. Building and sending results to instance service.", res.len(), kernel_summary.elapsed_micros);
     
     // after we hit our prime count, we send the results over to instance service and exit
-    let result_payload = ResultPayload {
+    progress.set_phase(Phase::Reporting);
+    let mut result_payload = ResultPayload {
+        protocol_version: PROTOCOL_VERSION,
         id: sieve_id.clone(),
-        primes: res
+        primes: res,
+        probe: probe_summary,
+        kernel: Some(kernel_summary),
+        partial: false,
+        digest: None,
+        signature: None,
     };
-    let prime_res = client.put("http://instance-service-headless:8080/result")
-        .header("content-type", "application/json")
-        .json(&result_payload)
-        .send()
-        .await?;
-
-    if prime_res.status() == StatusCode::OK {
-        tracing::info!("Prime results accepted by instance service. Exiting.");
-    } else {
-        let status_num = prime_res.status().as_u16();
-        let response_payload = prime_res.text().await?;
-        if status_num >= 400 && status_num < 500 {
-            tracing::error!("Client-side error response received: status code = {}, response = {}", status_num, response_payload);
-        } else {
-            tracing::warn!("Server-side error response received: status code = {}, response = {}", status_num, response_payload);
+    sieve_protocol::integrity::seal(&mut result_payload, hmac_key.as_deref());
+    let mut delivered = 0;
+    for s in sinks.iter() {
+        match s.submit(&result_payload).await {
+            Ok(()) => {
+                tracing::info!("Prime results accepted by '{}' sink.", s.name());
+                delivered += 1;
+            },
+            Err(e) => {
+                tracing::warn!("Failed to deliver prime results to '{}' sink. Error: {:?}", s.name(), e);
+            }
         }
     }
+    progress.set_phase(Phase::Done);
 
+    if delivered == 0 {
+        return Err(anyhow::anyhow!("Prime results were not accepted by any of the {} configured sinks", sinks.len()));
+    }
+    tracing::info!("Prime results delivered to {} of {} sinks. Exiting.", delivered, sinks.len());
     Ok(())
 }
 
-async fn basic_sieve(limit: usize) -> Box<dyn Iterator<Item = usize>> {
-    let mut is_prime = vec![true; (limit + 1).try_into().unwrap()];
+/// Pings every sink every `HEARTBEAT_INTERVAL_MS` (default 10s, 0 disables) until aborted. It
+/// keeps ticking during the kernel because the kernel runs on the blocking pool, not a runtime worker.
+fn start_heartbeats(sinks: Arc<Vec<Box<dyn sink::ResultSink>>>, sieve_id: &str) -> anyhow::Result<Option<tokio::task::JoinHandle<()>>> {
+    let interval_ms = env_u64("HEARTBEAT_INTERVAL_MS")?.unwrap_or(10000);
+    if interval_ms == 0 {
+        return Ok(None);
+    }
+
+    let payload = HeartbeatPayload { protocol_version: PROTOCOL_VERSION, id: String::from(sieve_id) };
+    Ok(Some(tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_millis(interval_ms));
+        loop {
+            ticker.tick().await;
+            for s in sinks.iter() {
+                if let Err(e) = s.heartbeat(&payload).await {
+                    tracing::debug!("Heartbeat to '{}' sink failed: {:?}", s.name(), e);
+                }
+            }
+        }
+    })))
+}
+
+async fn report_failure(sinks: &[Box<dyn sink::ResultSink>], sieve_id: &str, error: &anyhow::Error) {
+    let payload = FailurePayload {
+        protocol_version: PROTOCOL_VERSION,
+        id: String::from(sieve_id),
+        reason: error.to_string(),
+    };
+    for s in sinks {
+        if let Err(e) = s.fail(&payload).await {
+            tracing::warn!("Failed to report failure to '{}' sink. Error: {:?}", s.name(), e);
+        }
+    }
+}
+
+/// Runs the configured workload kernel. `KERNEL_INPUT` overrides the randomly chosen size.
+/// `SIEVE_RANGE_START` moves the byte sieve onto a segmented window starting at that value, which
+/// is how limits past 2^32 are reached without allocating the whole range. Blocks until done.
+fn run_kernel(kernel: Kernel, progress: &Progress) -> anyhow::Result<(Vec<u64>, KernelSummary)> {
+    let input = env_u64("KERNEL_INPUT")?;
+    let range_start = env_u64("SIEVE_RANGE_START")?;
+    let mut rng = rand::thread_rng();
+
+    let output = match (kernel, range_start) {
+        (Kernel::Sieve, Some(low)) => {
+            let width = input.unwrap_or_else(|| rng.gen_range(100000..=2500000));
+            let high = low.checked_add(width)
+                .ok_or_else(|| anyhow::anyhow!("Sieve range {} + {} overflows u64", low, width))?;
+            tracing::info!("Generating primes in the segmented range {}..={}", low, high);
+            let start = Instant::now();
+            let primes = kernels::segmented_sieve(low, high, progress);
+            kernels::KernelOutput {
+                primes,
+                summary: KernelSummary {
+                    kernel,
+                    input: high,
+                    range_start: Some(low),
+                    operations: width,
+                    elapsed_micros: start.elapsed().as_micros() as u64,
+                    memory_bytes: Some(kernels::SEGMENT_SIZE as u64),
+                    value: None,
+                },
+            }
+        },
+        (Kernel::Sieve, None) => {
+            let n = input.unwrap_or_else(|| rng.gen_range(100000..=2500000)) as usize;
+            tracing::info!("Generating primes up to a limit of {}", n);
+            let start = Instant::now();
+            let primes = basic_sieve(n, progress).collect::<Vec<_>>();
+            kernels::KernelOutput {
+                primes,
+                summary: KernelSummary {
+                    kernel,
+                    input: n as u64,
+                    range_start: None,
+                    operations: n as u64,
+                    elapsed_micros: start.elapsed().as_micros() as u64,
+                    memory_bytes: Some(n as u64 + 1),
+                    value: None,
+                },
+            }
+        },
+        (Kernel::BitSieve, _) => {
+            let n = input.unwrap_or_else(|| rng.gen_range(100000..=2500000)) as usize;
+            tracing::info!("Generating primes up to a limit of {} with the bit-packed sieve", n);
+            let start = Instant::now();
+            let sieve = bitsieve::BitSieve::new(n, progress);
+            let primes = sieve.primes();
+            kernels::KernelOutput {
+                primes,
+                summary: KernelSummary {
+                    kernel,
+                    input: n as u64,
+                    range_start: None,
+                    operations: n as u64,
+                    elapsed_micros: start.elapsed().as_micros() as u64,
+                    memory_bytes: Some(sieve.memory_bytes() as u64),
+                    value: None,
+                },
+            }
+        },
+        (Kernel::Factor, _) => {
+            let count = input.unwrap_or_else(|| rng.gen_range(1000..=3000));
+            tracing::info!("Factoring {} random semiprimes with Pollard's rho", count);
+            kernels::factor_semiprimes(count, progress)
+        },
+        (Kernel::LucasLehmer, _) => {
+            let max_exponent = input.unwrap_or_else(|| rng.gen_range(4000..=6000));
+            tracing::info!("Running Lucas-Lehmer tests on prime exponents up to {}", max_exponent);
+            kernels::lucas_lehmer_scan(max_exponent, progress)
+        },
+        (Kernel::PrimeCount, _) => {
+            let x = input.unwrap_or_else(|| rng.gen_range(100_000_000_000..=1_000_000_000_000));
+            kernels::check_prime_count_input(x)?;
+            tracing::info!("Counting primes up to {} with Meissel-Lehmer", x);
+            let out = kernels::count_primes(x, progress);
+            tracing::info!("Found {} primes up to {}", out.summary.value.unwrap_or(0), x);
+            out
+        },
+    };
+
+    Ok((output.primes, output.summary))
+}
+
+fn env_u64(name: &str) -> anyhow::Result<Option<u64>> {
+    match std::env::var(name) {
+        Ok(val) => Ok(Some(val.parse::<u64>().map_err(|e| anyhow::anyhow!("Invalid {} '{}': {}", name, val, e))?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn basic_sieve(limit: usize, progress: &Progress) -> Box<dyn Iterator<Item = u64>> {
+    let mut is_prime = vec![true; limit + 1];
     is_prime[0] = false;
     is_prime[1] = false;
     let limit_sqrt = (limit as f64).sqrt() as usize + 1;
-    sleep(Duration::from_millis(5000)).await;
+    std::thread::sleep(Duration::from_millis(5000));
+    progress.tick();
 
     for i in 2..limit_sqrt {
+        progress.tick();
         if is_prime[i] {
             let mut multiple = i * i;
             while multiple <= limit {
@@ -107,10 +309,10 @@ async fn basic_sieve(limit: usize) -> Box<dyn Iterator<Item = usize>> {
         }
     }
 
-    sleep(Duration::from_millis(5000)).await;
+    std::thread::sleep(Duration::from_millis(5000));
     Box::new(is_prime.into_iter()
         .enumerate()
-        .filter_map(|(p, is_prime)| if is_prime { Some(p) } else { None }))
+        .filter_map(|(p, is_prime)| if is_prime { Some(p as u64) } else { None }))
 }
 
 async fn query_until_dns_ready() -> anyhow::Result<()> {
@@ -124,7 +326,7 @@ async fn query_until_dns_ready() -> anyhow::Result<()> {
             Ok(resp) => {
                 tracing::debug!("DNS response: {:#?}", resp);
                 let answers: Vec<IpAddr> = resp.iter().collect();
-                if answers.len() > 0 {
+                if !answers.is_empty() {
                     tracing::info!("Received DNS answer after {} tries - continuing with processing", n);
                     return Ok(());
                 } else {
@@ -139,4 +341,34 @@ async fn query_until_dns_ready() -> anyhow::Result<()> {
     }
     tracing::warn!("No DNS response received in four attempts - continuing with processing.");
     Ok(())
-}
\ No newline at end of file
+}
+
+fn linkerd_shutdown_enabled() -> bool {
+    match std::env::var("LINKERD_SHUTDOWN") {
+        Ok(val) => val == "true",
+        Err(_) => false,
+    }
+}
+
+async fn shutdown_linkerd_proxy() {
+    let admin_addr = std::env::var("LINKERD_ADMIN_ADDR").unwrap_or_else(|_| String::from("localhost:4191"));
+    let shutdown_url = format!("http://{}/shutdown", admin_addr);
+    tracing::info!("Signalling linkerd proxy to shut down via {}", shutdown_url);
+
+    let client = reqwest::Client::new();
+    match client.post(shutdown_url.as_str())
+        .timeout(Duration::from_millis(5000))
+        .send()
+        .await {
+        Ok(resp) => {
+            if resp.status().is_success() {
+                tracing::info!("Linkerd proxy accepted shutdown request.");
+            } else {
+                tracing::warn!("Linkerd proxy rejected shutdown request with status code '{}'", resp.status().as_u16());
+            }
+        },
+        Err(e) => {
+            tracing::warn!("Failed to reach linkerd proxy admin endpoint - pod may not complete. Error: {:?}", e);
+        }
+    }
+}
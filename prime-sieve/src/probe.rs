@@ -0,0 +1,172 @@
+use std::time::{Duration, Instant};
+
+use sieve_protocol::{HistogramBucket, ProbeSummary};
+
+use crate::health::Progress;
+
+// upper bounds (in microseconds) for the RTT histogram buckets - anything slower lands in the overflow bucket
+const BUCKET_BOUNDS_MICROS: [u64; 12] = [250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 500000, 1000000];
+
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    pub requests: usize,
+    pub payload_bytes: usize,
+    pub response_bytes: usize,
+}
+
+impl ProbeConfig {
+    /// Reads the probe settings from the environment. Returns `None` when probing is disabled.
+    pub fn from_env() -> anyhow::Result<Option<ProbeConfig>> {
+        let requests = env_usize("PROBE_REQUESTS")?.unwrap_or(0);
+        if requests == 0 {
+            return Ok(None);
+        }
+
+        let payload_bytes = env_usize("PROBE_PAYLOAD_BYTES")?.unwrap_or(64);
+        let response_bytes = env_usize("PROBE_RESPONSE_BYTES")?.unwrap_or(payload_bytes);
+
+        Ok(Some(ProbeConfig { requests, payload_bytes, response_bytes }))
+    }
+}
+
+fn env_usize(name: &str) -> anyhow::Result<Option<usize>> {
+    match std::env::var(name) {
+        Ok(val) => val.parse::<usize>()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Invalid value '{}' for {}: {}", val, name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Sends `config.requests` sequential echo requests to instance service and summarizes the round trip times.
+#[tracing::instrument(skip(client, progress))]
+pub async fn run_probe(client: &reqwest::Client, base_url: &str, config: &ProbeConfig, progress: &Progress) -> ProbeSummary {
+    let echo_url = format!("{}/echo?size={}", base_url, config.response_bytes);
+    let body = vec![b'p'; config.payload_bytes];
+    let mut samples: Vec<u64> = Vec::with_capacity(config.requests);
+    let mut failures = 0;
+    let mut bytes_moved: u64 = 0;
+
+    tracing::info!("Starting latency probe with {} requests ({} byte payload, {} byte response)", config.requests, config.payload_bytes, config.response_bytes);
+    let probe_start = Instant::now();
+    for _ in 0..config.requests {
+        progress.tick();
+        let start = Instant::now();
+        let res = client.post(echo_url.as_str())
+            .header("content-type", "application/octet-stream")
+            .body(body.clone())
+            .send()
+            .await;
+
+        match res {
+            Ok(resp) if resp.status().is_success() => {
+                match resp.bytes().await {
+                    Ok(b) => {
+                        samples.push(start.elapsed().as_micros() as u64);
+                        bytes_moved += (config.payload_bytes + b.len()) as u64;
+                    },
+                    Err(e) => {
+                        tracing::debug!("Failed to read echo response body: {:?}", e);
+                        failures += 1;
+                    }
+                }
+            },
+            Ok(resp) => {
+                tracing::debug!("Echo request returned status code '{}'", resp.status().as_u16());
+                failures += 1;
+            },
+            Err(e) => {
+                tracing::debug!("Echo request failed: {:?}", e);
+                failures += 1;
+            }
+        }
+    }
+    let elapsed = probe_start.elapsed();
+
+    let summary = summarize(samples, failures, bytes_moved, config, elapsed);
+    tracing::info!("Latency probe complete - p50 {}us, p99 {}us, {} req/s, {} failures", summary.p50_micros, summary.p99_micros, summary.requests_per_sec, summary.failures);
+    summary
+}
+
+fn summarize(mut samples: Vec<u64>, failures: usize, bytes_moved: u64, config: &ProbeConfig, elapsed: Duration) -> ProbeSummary {
+    samples.sort_unstable();
+
+    let mut histogram: Vec<HistogramBucket> = BUCKET_BOUNDS_MICROS.iter()
+        .map(|b| HistogramBucket { le_micros: Some(*b), count: 0 })
+        .collect();
+    histogram.push(HistogramBucket { le_micros: None, count: 0 });
+    for s in &samples {
+        let idx = BUCKET_BOUNDS_MICROS.iter().position(|b| s <= b).unwrap_or(BUCKET_BOUNDS_MICROS.len());
+        histogram[idx].count += 1;
+    }
+
+    let percentile = |p: f64| -> u64 {
+        if samples.is_empty() {
+            return 0;
+        }
+        let rank = ((p / 100.0) * samples.len() as f64).ceil() as usize;
+        samples[rank.clamp(1, samples.len()) - 1]
+    };
+
+    let elapsed_micros = elapsed.as_micros() as u64;
+    let per_sec = |n: u64| -> u64 { (n * 1_000_000).checked_div(elapsed_micros).unwrap_or(0) };
+
+    ProbeSummary {
+        requests: config.requests,
+        failures,
+        payload_bytes: config.payload_bytes,
+        response_bytes: config.response_bytes,
+        elapsed_micros,
+        min_micros: samples.first().copied().unwrap_or(0),
+        max_micros: samples.last().copied().unwrap_or(0),
+        mean_micros: if samples.is_empty() { 0 } else { samples.iter().sum::<u64>() / samples.len() as u64 },
+        p50_micros: percentile(50.0),
+        p90_micros: percentile(90.0),
+        p99_micros: percentile(99.0),
+        requests_per_sec: per_sec(samples.len() as u64),
+        bytes_per_sec: per_sec(bytes_moved),
+        histogram,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(requests: usize) -> ProbeConfig {
+        ProbeConfig { requests, payload_bytes: 64, response_bytes: 64 }
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let samples: Vec<u64> = (1..=100).rev().map(|n| n * 10).collect();
+        let summary = summarize(samples, 0, 0, &config(100), Duration::from_secs(1));
+        assert_eq!((summary.min_micros, summary.max_micros, summary.mean_micros), (10, 1000, 505));
+        assert_eq!((summary.p50_micros, summary.p90_micros, summary.p99_micros), (500, 900, 990));
+        assert_eq!(summary.requests_per_sec, 100);
+
+        let summary = summarize(vec![42], 0, 0, &config(1), Duration::from_secs(1));
+        assert_eq!((summary.p50_micros, summary.p99_micros), (42, 42));
+    }
+
+    #[test]
+    fn histogram_buckets_are_inclusive_with_overflow() {
+        let samples = vec![1, 250, 251, 1_000_000, 1_000_001, 5_000_000];
+        let summary = summarize(samples, 2, 0, &config(8), Duration::from_secs(2));
+        let counts: Vec<(Option<u64>, u64)> = summary.histogram.iter().map(|b| (b.le_micros, b.count)).collect();
+        assert_eq!(counts.len(), BUCKET_BOUNDS_MICROS.len() + 1);
+        assert_eq!(counts[0], (Some(250), 2));
+        assert_eq!(counts[1], (Some(500), 1));
+        assert_eq!(counts[11], (Some(1_000_000), 1));
+        assert_eq!(counts[12], (None, 2));
+        assert_eq!(summary.histogram.iter().map(|b| b.count).sum::<u64>(), 6);
+        assert_eq!(summary.failures, 2);
+    }
+
+    #[test]
+    fn empty_probe_reports_zeroes() {
+        let summary = summarize(Vec::new(), 3, 0, &config(3), Duration::ZERO);
+        assert_eq!((summary.p50_micros, summary.mean_micros, summary.requests_per_sec), (0, 0, 0));
+        assert!(summary.histogram.iter().all(|b| b.count == 0));
+    }
+}
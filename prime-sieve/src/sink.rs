@@ -0,0 +1,418 @@
+use std::{path::PathBuf, sync::atomic::{AtomicBool, AtomicU32, Ordering}};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use reqwest::StatusCode;
+use serde::Serialize;
+use sieve_protocol::{FailurePayload, HeartbeatPayload, RegisterPayload, RegisterResponse, ResultPayload, OLDEST_SERVER_VERSION, PROTOCOL_VERSION};
+use sieve_protocol::redis_keys::{index_key, worker_key};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+
+/// A destination for sieve registrations and results. The sieve fans every event out to all
+/// configured sinks, so results still land somewhere when instance service is the thing under test.
+#[async_trait]
+pub trait ResultSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn register(&self, payload: &RegisterPayload) -> anyhow::Result<()>;
+
+    async fn submit(&self, payload: &ResultPayload) -> anyhow::Result<()>;
+
+    /// Liveness ping while the sieve works. Only sinks that track worker state care.
+    async fn heartbeat(&self, _payload: &HeartbeatPayload) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Reports that the sieve gave up without results.
+    async fn fail(&self, _payload: &FailurePayload) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum SinkRecord<'a> {
+    Register(&'a RegisterPayload),
+    Result(&'a ResultPayload),
+    Failure(&'a FailurePayload),
+}
+
+/// Builds the sinks listed in `RESULT_SINKS` (comma separated). Defaults to the instance service API.
+/// The HMAC key lets the http sink re-sign results it downgrades to an older protocol version.
+pub fn sinks_from_env(client: &reqwest::Client, instance_url: &str, hmac_key: Option<&[u8]>) -> anyhow::Result<Vec<Box<dyn ResultSink>>> {
+    let mut sinks: Vec<Box<dyn ResultSink>> = Vec::new();
+
+    for name in configured_sinks() {
+        let sink: Box<dyn ResultSink> = match name.as_str() {
+            "http" => Box::new(HttpSink::new(client.clone(), instance_url, hmac_key)),
+            "stdout" => Box::new(StdoutSink),
+            "file" => {
+                let path = std::env::var("RESULT_FILE_PATH").unwrap_or_else(|_| String::from("/tmp/sieve-results.jsonl"));
+                Box::new(FileSink { path: PathBuf::from(path), lock: Mutex::new(()) })
+            },
+            "redis" => {
+                let url = std::env::var("SINK_REDIS_URL")
+                    .map_err(|_| anyhow::anyhow!("'redis' result sink requires SINK_REDIS_URL to be set"))?;
+                let client = redis::Client::open(url.as_str())?;
+                // same run and expiry settings as instance service, so both write the same run
+                let run_id = std::env::var("RUN_ID").unwrap_or_else(|_| String::from("default"));
+                let ttl_secs = match std::env::var("RUN_TTL_SECS") {
+                    Ok(val) => match val.parse::<usize>() {
+                        Ok(0) => None,
+                        Ok(secs) => Some(secs),
+                        Err(e) => return Err(anyhow::anyhow!("Invalid value '{}' for RUN_TTL_SECS: {}", val, e)),
+                    },
+                    Err(_) => None,
+                };
+                Box::new(RedisSink { client, run_id, ttl_secs })
+            },
+            other => return Err(anyhow::anyhow!("Unknown result sink '{}' - expected http, stdout, file or redis", other)),
+        };
+        sinks.push(sink);
+    }
+
+    if sinks.is_empty() {
+        return Err(anyhow::anyhow!("RESULT_SINKS is set but names no sinks"));
+    }
+    tracing::info!("Configured result sinks: {}", sinks.iter().map(|s| s.name()).collect::<Vec<_>>().join(", "));
+    Ok(sinks)
+}
+
+/// Whether results go to stdout, in which case logs have to go elsewhere to keep it parseable.
+pub fn stdout_sink_selected() -> bool {
+    configured_sinks().iter().any(|s| s == "stdout")
+}
+
+fn configured_sinks() -> Vec<String> {
+    let configured = std::env::var("RESULT_SINKS").unwrap_or_else(|_| String::from("http"));
+    configured.split(',').map(|s| s.trim().to_ascii_lowercase()).filter(|s| !s.is_empty()).collect()
+}
+
+pub struct HttpSink {
+    client: reqwest::Client,
+    base_url: String,
+    // the signature covers the protocol version, so results re-stamped for an older server are re-signed
+    hmac_key: Option<Vec<u8>>,
+    // version agreed with instance service at register time
+    negotiated_version: AtomicU32,
+    // set when instance service only speaks a protocol we can't report to
+    incompatible: AtomicBool,
+}
+
+impl HttpSink {
+    pub fn new(client: reqwest::Client, base_url: &str, hmac_key: Option<&[u8]>) -> HttpSink {
+        HttpSink {
+            client,
+            base_url: String::from(base_url),
+            hmac_key: hmac_key.map(<[u8]>::to_vec),
+            negotiated_version: AtomicU32::new(PROTOCOL_VERSION),
+            incompatible: AtomicBool::new(false),
+        }
+    }
+
+    // heartbeats and failure reports arrived in version 3, older servers would answer with a 404
+    fn tracks_liveness(&self) -> bool {
+        !self.incompatible.load(Ordering::Relaxed) && self.negotiated_version.load(Ordering::Relaxed) >= 3
+    }
+
+    async fn post_status<T: Serialize + Sync>(&self, path: &str, payload: &T) -> anyhow::Result<()> {
+        let resp = self.client.post(format!("{}/{}", self.base_url, path))
+            .header("content-type", "application/json")
+            .json(payload)
+            .send()
+            .await?;
+        if resp.status() != StatusCode::OK {
+            let status_num = resp.status().as_u16();
+            let response_payload = resp.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("instance service returned status code '{}' for {}: {}", status_num, path, response_payload));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ResultSink for HttpSink {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    async fn register(&self, payload: &RegisterPayload) -> anyhow::Result<()> {
+        let resp = self.client.post(format!("{}/register", self.base_url))
+            .header("content-type", "application/json")
+            .json(payload)
+            .send()
+            .await?;
+
+        if resp.status() != StatusCode::CREATED {
+            let status_num = resp.status().as_u16();
+            let response_payload = resp.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("instance service returned status code '{}' for register: {}", status_num, response_payload));
+        }
+
+        // instance service from before versioning answers with an empty body, which means version 1
+        let body = resp.bytes().await?;
+        let server_version = match serde_json::from_slice::<RegisterResponse>(&body) {
+            Ok(r) => r.protocol_version,
+            Err(_) => 1,
+        };
+        match sieve_protocol::negotiate(server_version, OLDEST_SERVER_VERSION) {
+            Ok(version) => {
+                tracing::info!("Negotiated protocol version {} with instance service", version);
+                self.negotiated_version.store(version, Ordering::Relaxed);
+                Ok(())
+            },
+            Err(e) => {
+                self.incompatible.store(true, Ordering::Relaxed);
+                Err(anyhow::anyhow!("instance service protocol is incompatible: {}", e))
+            }
+        }
+    }
+
+    async fn submit(&self, payload: &ResultPayload) -> anyhow::Result<()> {
+        if self.incompatible.load(Ordering::Relaxed) {
+            return Err(anyhow::anyhow!("not sending results to an instance service with an incompatible protocol"));
+        }
+
+        let version = self.negotiated_version.load(Ordering::Relaxed);
+        let request = self.client.put(format!("{}/result", self.base_url))
+            .header("content-type", "application/json");
+        let resp = if version == payload.protocol_version {
+            request.json(payload).send().await?
+        } else {
+            let mut stamped = payload.clone();
+            stamped.protocol_version = version;
+            sieve_protocol::integrity::seal(&mut stamped, self.hmac_key.as_deref());
+            request.json(&stamped).send().await?
+        };
+
+        if resp.status() == StatusCode::OK {
+            return Ok(());
+        }
+
+        let status_num = resp.status().as_u16();
+        let response_payload = resp.text().await?;
+        if (400..500).contains(&status_num) {
+            tracing::error!("Client-side error response received: status code = {}, response = {}", status_num, response_payload);
+        } else {
+            tracing::warn!("Server-side error response received: status code = {}, response = {}", status_num, response_payload);
+        }
+        Err(anyhow::anyhow!("instance service returned status code '{}' for result", status_num))
+    }
+
+    async fn heartbeat(&self, payload: &HeartbeatPayload) -> anyhow::Result<()> {
+        if !self.tracks_liveness() {
+            return Ok(());
+        }
+        let mut stamped = payload.clone();
+        stamped.protocol_version = self.negotiated_version.load(Ordering::Relaxed);
+        self.post_status("heartbeat", &stamped).await
+    }
+
+    async fn fail(&self, payload: &FailurePayload) -> anyhow::Result<()> {
+        if !self.tracks_liveness() {
+            return Ok(());
+        }
+        let mut stamped = payload.clone();
+        stamped.protocol_version = self.negotiated_version.load(Ordering::Relaxed);
+        self.post_status("failure", &stamped).await
+    }
+}
+
+/// Prints one JSON record per line on stdout - logs move to stderr while this sink is configured.
+pub struct StdoutSink;
+
+#[async_trait]
+impl ResultSink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    async fn register(&self, payload: &RegisterPayload) -> anyhow::Result<()> {
+        println!("{}", serde_json::to_string(&SinkRecord::Register(payload))?);
+        Ok(())
+    }
+
+    async fn submit(&self, payload: &ResultPayload) -> anyhow::Result<()> {
+        println!("{}", serde_json::to_string(&SinkRecord::Result(payload))?);
+        Ok(())
+    }
+
+    async fn fail(&self, payload: &FailurePayload) -> anyhow::Result<()> {
+        println!("{}", serde_json::to_string(&SinkRecord::Failure(payload))?);
+        Ok(())
+    }
+}
+
+pub struct FileSink {
+    path: PathBuf,
+    // keeps concurrent writers from interleaving lines
+    lock: Mutex<()>,
+}
+
+impl FileSink {
+    async fn append(&self, record: &SinkRecord<'_>) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().await;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ResultSink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn register(&self, payload: &RegisterPayload) -> anyhow::Result<()> {
+        self.append(&SinkRecord::Register(payload)).await
+    }
+
+    async fn submit(&self, payload: &ResultPayload) -> anyhow::Result<()> {
+        self.append(&SinkRecord::Result(payload)).await
+    }
+
+    async fn fail(&self, payload: &FailurePayload) -> anyhow::Result<()> {
+        self.append(&SinkRecord::Failure(payload)).await
+    }
+}
+
+/// Writes worker records in instance service's Redis layout (see `sieve_protocol::redis_keys`), so
+/// the run can be read, listed and purged the same way when instance service is out of the loop.
+pub struct RedisSink {
+    client: redis::Client,
+    run_id: String,
+    ttl_secs: Option<usize>,
+}
+
+impl RedisSink {
+    // the hash and the run's index are written together, with the run's TTL refreshed on both
+    async fn write(&self, id: &str, fields: &[(&str, String)]) -> anyhow::Result<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_multiple(worker_key(&self.run_id, id), fields).ignore()
+            .sadd(index_key(&self.run_id), id).ignore();
+        if let Some(secs) = self.ttl_secs {
+            pipe.expire(worker_key(&self.run_id, id), secs).ignore()
+                .expire(index_key(&self.run_id), secs).ignore();
+        }
+        let mut con = self.client.get_async_connection().await?;
+        pipe.query_async::<_, ()>(&mut con).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ResultSink for RedisSink {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn register(&self, payload: &RegisterPayload) -> anyhow::Result<()> {
+        let now = Utc::now().to_rfc3339();
+        let mut fields = vec![
+            ("id", payload.id.clone()),
+            ("protocol_version", payload.protocol_version.to_string()),
+            ("status", String::from("registered")),
+            ("registered_at", now.clone()),
+            ("last_seen", now),
+        ];
+        if let Some(start) = &payload.start {
+            fields.push(("start", serde_json::to_string(start)?));
+        }
+        self.write(&payload.id, &fields).await
+    }
+
+    async fn submit(&self, payload: &ResultPayload) -> anyhow::Result<()> {
+        let max_prime = payload.primes.last()
+            .ok_or_else(|| anyhow::anyhow!("refusing to write an empty prime list for worker {}", payload.id))?;
+        let now = Utc::now().to_rfc3339();
+        let mut fields = vec![
+            ("id", payload.id.clone()),
+            ("protocol_version", payload.protocol_version.to_string()),
+            ("status", String::from(if payload.partial { "partial" } else { "completed" })),
+            ("completed_at", now.clone()),
+            ("last_seen", now),
+            ("quantity", payload.primes.len().to_string()),
+            ("max_prime", max_prime.to_string()),
+        ];
+        if let Some(digest) = &payload.digest {
+            fields.push(("result_digest", digest.clone()));
+        }
+        if let Some(probe) = &payload.probe {
+            fields.push(("probe", serde_json::to_string(probe)?));
+        }
+        if let Some(kernel) = &payload.kernel {
+            fields.push(("kernel", serde_json::to_string(kernel)?));
+        }
+        self.write(&payload.id, &fields).await
+    }
+
+    async fn fail(&self, payload: &FailurePayload) -> anyhow::Result<()> {
+        let fields = [
+            ("id", payload.id.clone()),
+            ("protocol_version", payload.protocol_version.to_string()),
+            ("status", String::from("failed")),
+            ("last_seen", Utc::now().to_rfc3339()),
+            ("failure", payload.reason.clone()),
+        ];
+        self.write(&payload.id, &fields).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpResponse, HttpServer, web};
+
+    // stands in for a version 2 instance service that checks signatures
+    async fn v2_register() -> HttpResponse {
+        HttpResponse::Created().json(RegisterResponse { protocol_version: 2 })
+    }
+
+    async fn v2_result(payload: web::Json<ResultPayload>) -> HttpResponse {
+        match sieve_protocol::integrity::verify(&payload, Some(b"secret")) {
+            Ok(()) if payload.protocol_version == 2 => HttpResponse::Ok().finish(),
+            Ok(()) => HttpResponse::BadRequest().body(format!("unexpected version {}", payload.protocol_version)),
+            Err(e) => HttpResponse::Unauthorized().body(e.to_string()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn downgraded_results_are_re_signed() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(|| App::new()
+                .route("/register", web::post().to(v2_register))
+                .route("/result", web::put().to(v2_result)))
+            .workers(1)
+            .listen(listener).unwrap()
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let sink = HttpSink::new(reqwest::Client::new(), &format!("http://127.0.0.1:{}", port), Some(b"secret"));
+        let register = RegisterPayload { protocol_version: PROTOCOL_VERSION, id: String::from("abc"), start: None };
+        sink.register(&register).await.unwrap();
+        assert_eq!(sink.negotiated_version.load(Ordering::Relaxed), 2);
+
+        let mut result = ResultPayload {
+            protocol_version: PROTOCOL_VERSION,
+            id: String::from("abc"),
+            primes: vec![2, 3, 5, 7],
+            probe: None,
+            kernel: None,
+            partial: false,
+            digest: None,
+            signature: None,
+        };
+        sieve_protocol::integrity::seal(&mut result, Some(b"secret"));
+        sink.submit(&result).await.unwrap();
+
+        handle.stop(false).await;
+    }
+}
@@ -0,0 +1,236 @@
+//! Content digests and HMAC signatures for result submissions.
+//!
+//! The digest is a SHA-256 over the primes (each as 8 big-endian bytes) and catches corruption on
+//! the way through proxies. The signature is an HMAC-SHA256 keyed with a secret shared between the
+//! sieves and instance service, covering the protocol version, worker ID and digest, so a result
+//! can't be forged or moved to another worker without the key. From version 3 it also covers the
+//! `partial` flag and the kernel summary, which decide how instance service files the result.
+
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::ResultPayload;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// The digest sent with the payload doesn't match its primes.
+    DigestMismatch { expected: String, received: String },
+    /// A key is configured but the payload carries no signature.
+    MissingSignature,
+    /// A signature is required but there's no digest for it to cover.
+    MissingDigest,
+    /// The signature isn't valid hex or doesn't match.
+    BadSignature,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::DigestMismatch { expected, received } =>
+                write!(f, "primes digest mismatch - payload says {} but primes hash to {}", received, expected),
+            IntegrityError::MissingSignature => write!(f, "result is not signed but a signature is required"),
+            IntegrityError::MissingDigest => write!(f, "result has a signature but no digest"),
+            IntegrityError::BadSignature => write!(f, "result signature does not match"),
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Loads the shared signing key from `RESULT_HMAC_KEY`, or from the file named by
+/// `RESULT_HMAC_KEY_FILE` (a mounted secret). Returns `None` when neither is set.
+pub fn key_from_env() -> std::io::Result<Option<Vec<u8>>> {
+    let key = match (std::env::var("RESULT_HMAC_KEY"), std::env::var("RESULT_HMAC_KEY_FILE")) {
+        (Ok(key), _) => key.into_bytes(),
+        (Err(_), Ok(path)) => {
+            let mut key = std::fs::read(&path)?;
+            // secrets written by hand usually end in a newline that isn't part of the key
+            while key.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+                key.pop();
+            }
+            key
+        },
+        (Err(_), Err(_)) => return Ok(None),
+    };
+
+    if key.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "result HMAC key is configured but empty"));
+    }
+    Ok(Some(key))
+}
+
+/// Hex encoded SHA-256 over the primes.
+pub fn digest_primes(primes: &[u64]) -> String {
+    let mut hasher = Sha256::new();
+    for p in primes {
+        hasher.update(p.to_be_bytes());
+    }
+    to_hex(&hasher.finalize())
+}
+
+/// Fills in the digest and, when a key is given, the signature.
+pub fn seal(payload: &mut ResultPayload, key: Option<&[u8]>) {
+    let digest = digest_primes(&payload.primes);
+    payload.signature = key.map(|k| to_hex(&mac(k, payload, &digest).finalize().into_bytes()));
+    payload.digest = Some(digest);
+}
+
+/// Checks a received payload. Unsigned payloads are accepted only when no key is configured;
+/// a digest is always checked when present.
+pub fn verify(payload: &ResultPayload, key: Option<&[u8]>) -> Result<(), IntegrityError> {
+    let expected = digest_primes(&payload.primes);
+    if let Some(received) = &payload.digest {
+        if *received != expected {
+            return Err(IntegrityError::DigestMismatch { expected, received: received.clone() });
+        }
+    }
+
+    let key = match key {
+        Some(k) => k,
+        None => return Ok(()),
+    };
+    let signature = payload.signature.as_ref().ok_or(IntegrityError::MissingSignature)?;
+    if payload.digest.is_none() {
+        return Err(IntegrityError::MissingDigest);
+    }
+    let signature = from_hex(signature).ok_or(IntegrityError::BadSignature)?;
+
+    // verify_slice compares in constant time
+    mac(key, payload, &expected)
+        .verify_slice(&signature)
+        .map_err(|_| IntegrityError::BadSignature)
+}
+
+fn mac(key: &[u8], payload: &ResultPayload, digest: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}\n{}\n{}", payload.protocol_version, payload.id, digest).as_bytes());
+    // version 2 sieves sign without these, and their payloads can't carry a partial flag anyway.
+    // both ends build the kernel line from the same struct, so its Debug form is stable enough
+    if payload.protocol_version >= 3 {
+        mac.update(format!("\n{}\n{:?}", payload.partial, payload.kernel).as_bytes());
+    }
+    mac
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+
+    fn payload() -> ResultPayload {
+        ResultPayload {
+            protocol_version: PROTOCOL_VERSION,
+            id: String::from("abc"),
+            primes: vec![2, 3, 5, 7, 4294967311],
+            probe: None,
+            kernel: None,
+            partial: false,
+            digest: None,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn sealed_payload_verifies() {
+        let mut p = payload();
+        seal(&mut p, Some(b"secret"));
+        assert!(p.digest.is_some());
+        assert_eq!(verify(&p, Some(b"secret")), Ok(()));
+    }
+
+    #[test]
+    fn unsigned_payload_is_fine_without_key() {
+        let p = payload();
+        assert_eq!(verify(&p, None), Ok(()));
+    }
+
+    #[test]
+    fn unsigned_payload_is_rejected_with_key() {
+        let mut p = payload();
+        seal(&mut p, None);
+        assert_eq!(verify(&p, Some(b"secret")), Err(IntegrityError::MissingSignature));
+    }
+
+    #[test]
+    fn corrupted_primes_fail_digest() {
+        let mut p = payload();
+        seal(&mut p, Some(b"secret"));
+        p.primes[1] = 4;
+        assert!(matches!(verify(&p, Some(b"secret")), Err(IntegrityError::DigestMismatch { .. })));
+    }
+
+    #[test]
+    fn tampered_id_or_wrong_key_fails_signature() {
+        let mut p = payload();
+        seal(&mut p, Some(b"secret"));
+
+        let mut moved = p.clone();
+        moved.id = String::from("someone-else");
+        assert_eq!(verify(&moved, Some(b"secret")), Err(IntegrityError::BadSignature));
+
+        assert_eq!(verify(&p, Some(b"other")), Err(IntegrityError::BadSignature));
+    }
+
+    #[test]
+    fn tampered_partial_flag_or_kernel_fails_signature() {
+        let mut p = payload();
+        p.partial = true;
+        p.kernel = Some(crate::KernelSummary {
+            kernel: crate::Kernel::Factor,
+            input: 10,
+            range_start: None,
+            operations: 500,
+            elapsed_micros: 1200,
+            memory_bytes: None,
+            value: None,
+        });
+        seal(&mut p, Some(b"secret"));
+        assert_eq!(verify(&p, Some(b"secret")), Ok(()));
+
+        let mut flipped = p.clone();
+        flipped.partial = false;
+        assert_eq!(verify(&flipped, Some(b"secret")), Err(IntegrityError::BadSignature));
+
+        let mut rekerneled = p.clone();
+        rekerneled.kernel.as_mut().unwrap().input = 11;
+        assert_eq!(verify(&rekerneled, Some(b"secret")), Err(IntegrityError::BadSignature));
+    }
+
+    #[test]
+    fn version_2_signatures_still_verify() {
+        let mut p = payload();
+        p.protocol_version = 2;
+        let digest = digest_primes(&p.primes);
+        let mut legacy = HmacSha256::new_from_slice(b"secret").unwrap();
+        legacy.update(format!("2\nabc\n{}", digest).as_bytes());
+        p.digest = Some(digest);
+        p.signature = Some(to_hex(&legacy.finalize().into_bytes()));
+        assert_eq!(verify(&p, Some(b"secret")), Ok(()));
+    }
+
+    #[test]
+    fn malformed_signature_is_rejected() {
+        let mut p = payload();
+        seal(&mut p, Some(b"secret"));
+        p.signature = Some(String::from("zz1"));
+        assert_eq!(verify(&p, Some(b"secret")), Err(IntegrityError::BadSignature));
+    }
+}
@@ -0,0 +1,321 @@
+//! Wire types shared by `prime-sieve` and `instance-service`.
+//!
+//! Every payload carries a `protocol_version`. Sieves send the newest version they speak when
+//! registering, instance service answers with the version both sides will use (see [`negotiate`]),
+//! and results are stamped with that version. Payloads from sieves built before versioning existed
+//! have no version field and are read as version 1.
+
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+pub mod integrity;
+pub mod redis_keys;
+
+/// The protocol version this build speaks.
+///
+/// * 1 - unversioned `{id}` / `{id, primes}` payloads, primes limited to i32 by instance service
+/// * 2 - u64 primes, start/probe/kernel reports and explicit versions
+/// * 3 - heartbeats, failure reports and partial results
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Oldest sieve protocol instance service still accepts. Version 1 payloads are a subset of
+/// version 2 and parse into the same types.
+pub const OLDEST_SIEVE_VERSION: u32 = 1;
+
+/// Oldest instance service protocol a sieve will report to. Version 1 servers truncate primes
+/// past i32, so results sent to them would be wrong.
+pub const OLDEST_SERVER_VERSION: u32 = 2;
+
+fn legacy_version() -> u32 {
+    1
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionError {
+    pub requested: u32,
+    pub oldest_supported: u32,
+    pub newest_supported: u32,
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "protocol version {} is not supported (supported versions {}..={})",
+            self.requested, self.oldest_supported, self.newest_supported)
+    }
+}
+
+impl std::error::Error for VersionError {}
+
+/// Picks the version both sides will use: the older of the two, as long as this side still
+/// supports it. `oldest_supported` is [`OLDEST_SIEVE_VERSION`] on the server and
+/// [`OLDEST_SERVER_VERSION`] on the sieve.
+pub fn negotiate(peer_version: u32, oldest_supported: u32) -> Result<u32, VersionError> {
+    let version = peer_version.min(PROTOCOL_VERSION);
+    if version < oldest_supported {
+        return Err(VersionError {
+            requested: peer_version,
+            oldest_supported,
+            newest_supported: PROTOCOL_VERSION,
+        });
+    }
+    Ok(version)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegisterPayload {
+    #[serde(default = "legacy_version")]
+    pub protocol_version: u32,
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<StartInfo>,
+}
+
+/// Body of a successful register response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RegisterResponse {
+    pub protocol_version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResultPayload {
+    #[serde(default = "legacy_version")]
+    pub protocol_version: u32,
+    pub id: String,
+    pub primes: Vec<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<ProbeSummary>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<KernelSummary>,
+    // the primes only cover part of the requested work
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub partial: bool,
+    // hex SHA-256 over the primes, see `integrity`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    // hex HMAC-SHA256 over version, id, digest and (from version 3) the partial flag and kernel
+    // summary when a shared key is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// Sent periodically while a sieve is working so instance service can tell a slow sieve from one
+/// that has disappeared. Version 3 and up.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HeartbeatPayload {
+    pub protocol_version: u32,
+    pub id: String,
+}
+
+/// Sent when a sieve gives up without results. Version 3 and up.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailurePayload {
+    pub protocol_version: u32,
+    pub id: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum JitterDistribution {
+    None,
+    Uniform,
+    Exponential,
+}
+
+impl FromStr for JitterDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "" => Ok(JitterDistribution::None),
+            "uniform" => Ok(JitterDistribution::Uniform),
+            "exponential" => Ok(JitterDistribution::Exponential),
+            other => Err(format!("unknown jitter distribution '{}' - expected none, uniform or exponential", other)),
+        }
+    }
+}
+
+/// What the sieve actually did before starting work - sent along with the register request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StartInfo {
+    pub distribution: JitterDistribution,
+    pub jitter_ms: u64,
+    pub stagger_ms: u64,
+    pub started_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HistogramBucket {
+    // None marks the overflow bucket
+    pub le_micros: Option<u64>,
+    pub count: u64,
+}
+
+/// Round trip summary from the sieve's latency probe against the echo endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProbeSummary {
+    pub requests: usize,
+    pub failures: usize,
+    pub payload_bytes: usize,
+    pub response_bytes: usize,
+    pub elapsed_micros: u64,
+    pub min_micros: u64,
+    pub max_micros: u64,
+    pub mean_micros: u64,
+    pub p50_micros: u64,
+    pub p90_micros: u64,
+    pub p99_micros: u64,
+    pub requests_per_sec: u64,
+    pub bytes_per_sec: u64,
+    pub histogram: Vec<HistogramBucket>,
+}
+
+/// The compute workload a sieve pod runs. The byte sieves lean on memory bandwidth, the others
+/// are ALU-bound so both profiles can be compared on the same nodes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kernel {
+    Sieve,
+    BitSieve,
+    Factor,
+    LucasLehmer,
+    PrimeCount,
+}
+
+impl FromStr for Kernel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sieve" | "" => Ok(Kernel::Sieve),
+            "bit-sieve" => Ok(Kernel::BitSieve),
+            "factor" => Ok(Kernel::Factor),
+            "lucas-lehmer" => Ok(Kernel::LucasLehmer),
+            "prime-count" => Ok(Kernel::PrimeCount),
+            other => Err(format!("unknown kernel '{}' - expected sieve, bit-sieve, factor, lucas-lehmer or prime-count", other)),
+        }
+    }
+}
+
+/// Sent along with the result so instance service knows which kernel produced the primes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KernelSummary {
+    pub kernel: Kernel,
+    // sieve limit, number of semiprimes, largest exponent or counting bound depending on kernel
+    pub input: u64,
+    // lower bound when the sieve ran over a segmented window instead of starting at zero
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range_start: Option<u64>,
+    // kernel specific unit of work - rho iterations, squarings or phi evaluations
+    pub operations: u64,
+    pub elapsed_micros: u64,
+    // size of the sieve's working set, reported by the sieve kernels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    // the prime count for the counting kernel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn unversioned_register_is_read_as_v1() {
+        let payload: RegisterPayload = serde_json::from_value(json!({ "id": "abc" })).unwrap();
+        assert_eq!(payload.protocol_version, 1);
+        assert!(payload.start.is_none());
+    }
+
+    #[test]
+    fn unversioned_result_is_read_as_v1() {
+        let payload: ResultPayload = serde_json::from_value(json!({ "id": "abc", "primes": [2, 3, 5] })).unwrap();
+        assert_eq!(payload.protocol_version, 1);
+        assert_eq!(payload.primes, vec![2, 3, 5]);
+        assert!(payload.probe.is_none());
+        assert!(payload.kernel.is_none());
+    }
+
+    #[test]
+    fn result_round_trips_with_version_and_summaries() {
+        let payload = ResultPayload {
+            protocol_version: PROTOCOL_VERSION,
+            id: String::from("abc"),
+            primes: vec![4294967311, 4294967357],
+            probe: None,
+            kernel: Some(KernelSummary {
+                kernel: Kernel::LucasLehmer,
+                input: 5000,
+                range_start: None,
+                operations: 12,
+                elapsed_micros: 34,
+                memory_bytes: None,
+                value: None,
+            }),
+            partial: false,
+            digest: None,
+            signature: None,
+        };
+
+        let value = serde_json::to_value(&payload).unwrap();
+        assert_eq!(value["protocol_version"], json!(PROTOCOL_VERSION));
+        assert_eq!(value["kernel"]["kernel"], json!("lucas-lehmer"));
+        assert!(value.get("probe").is_none());
+        assert!(value.get("partial").is_none());
+
+        let parsed: ResultPayload = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.primes, payload.primes);
+        assert_eq!(parsed.kernel, payload.kernel);
+    }
+
+    #[test]
+    fn unknown_fields_from_newer_peers_are_ignored() {
+        let payload: RegisterPayload = serde_json::from_value(json!({
+            "protocol_version": PROTOCOL_VERSION + 1,
+            "id": "abc",
+            "something_new": { "nested": true }
+        })).unwrap();
+        assert_eq!(payload.protocol_version, PROTOCOL_VERSION + 1);
+    }
+
+    #[test]
+    fn start_info_uses_lowercase_distribution() {
+        let start: StartInfo = serde_json::from_value(json!({
+            "distribution": "exponential",
+            "jitter_ms": 10,
+            "stagger_ms": 0,
+            "started_at": "2021-11-20T10:00:00Z"
+        })).unwrap();
+        assert_eq!(start.distribution, JitterDistribution::Exponential);
+    }
+
+    #[test]
+    fn negotiate_picks_older_version() {
+        assert_eq!(negotiate(PROTOCOL_VERSION, OLDEST_SIEVE_VERSION), Ok(PROTOCOL_VERSION));
+        assert_eq!(negotiate(PROTOCOL_VERSION + 3, OLDEST_SIEVE_VERSION), Ok(PROTOCOL_VERSION));
+        assert_eq!(negotiate(1, OLDEST_SIEVE_VERSION), Ok(1));
+    }
+
+    #[test]
+    fn negotiate_rejects_too_old_peer() {
+        let err = negotiate(1, OLDEST_SERVER_VERSION).unwrap_err();
+        assert_eq!(err.requested, 1);
+        assert_eq!(err.oldest_supported, OLDEST_SERVER_VERSION);
+    }
+
+    #[test]
+    fn kernel_and_distribution_parse_from_env_strings() {
+        assert_eq!("bit-sieve".parse::<Kernel>(), Ok(Kernel::BitSieve));
+        assert_eq!("Prime-Count".parse::<Kernel>(), Ok(Kernel::PrimeCount));
+        assert!("nope".parse::<Kernel>().is_err());
+        assert_eq!("uniform".parse::<JitterDistribution>(), Ok(JitterDistribution::Uniform));
+    }
+}
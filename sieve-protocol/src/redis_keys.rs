@@ -0,0 +1,35 @@
+//! Redis key layout for worker records, shared by instance service's store and the sieve's redis
+//! sink so both write runs that `/admin/runs` can list and purge.
+//!
+//! Each worker is a hash at `prime-gen:{run}:worker:{id}` and the run keeps a set of its worker
+//! IDs at `prime-gen:{run}:workers`.
+
+pub const KEY_ROOT: &str = "prime-gen";
+
+pub fn worker_key(run_id: &str, id: &str) -> String {
+    format!("{}:{}:worker:{}", KEY_ROOT, run_id, id)
+}
+
+pub fn index_key(run_id: &str) -> String {
+    format!("{}:{}:workers", KEY_ROOT, run_id)
+}
+
+/// Pattern matching every run's index key, for a SCAN.
+pub fn index_pattern() -> String {
+    index_key("*")
+}
+
+pub fn run_from_index_key(key: &str) -> Option<&str> {
+    key.strip_prefix(KEY_ROOT)?.strip_prefix(':')?.strip_suffix(":workers")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_id_comes_from_index_key() {
+        assert_eq!(run_from_index_key(&index_key("run-a")), Some("run-a"));
+        assert_eq!(run_from_index_key(&worker_key("run-a", "abc")), None);
+    }
+}